@@ -0,0 +1,7 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|image: &[u8]| {
+    simplefs::mount_and_read_all(image);
+});
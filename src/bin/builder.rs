@@ -1,21 +1,62 @@
 use std::mem::size_of;
+use std::ops::Range;
 
 use bytes::{BufMut, Bytes, BytesMut};
-use simplefs::{DirEntry, FilesystemHeader};
+use simplefs::{Crc32, DirEntry, Error, FileSystem, FilesystemHeader, HeaderFlags, Storage};
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 pub enum BuilderError {
-    OutOfSpace,
+    OutOfSpace { needed: usize, capacity: usize },
     TooManyFiles,
-    FileTooBig,
+    FileTooBig { size: usize, max: u64 },
+    ContentTypeTooLong,
+    LogicalLengthTooShort,
+    OverlappingFile { offset: usize },
+    NoFiles,
+    ImageTooLargeForFormat { size: usize },
+    VersionDowngrade { version: u16 },
+    InvalidDirectoryAlignment { alignment: usize },
+    AlignmentTooLarge { alignment: usize },
 }
 
 impl std::fmt::Display for BuilderError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            BuilderError::OutOfSpace => write!(f, "capacity exceeded"),
+            BuilderError::OutOfSpace { needed, capacity } => write!(
+                f,
+                "capacity exceeded: {} bytes needed, {} available",
+                needed, capacity
+            ),
             BuilderError::TooManyFiles => write!(f, "too many files"),
-            BuilderError::FileTooBig => write!(f, "file too big"),
+            BuilderError::FileTooBig { size, max } => {
+                write!(f, "file too big: {} bytes, max {}", size, max)
+            }
+            BuilderError::ContentTypeTooLong => write!(f, "content type too long"),
+            BuilderError::LogicalLengthTooShort => {
+                write!(f, "sparse file logical length shorter than its data")
+            }
+            BuilderError::OverlappingFile { offset } => {
+                write!(f, "file at offset {} overlaps other data", offset)
+            }
+            BuilderError::NoFiles => write!(f, "no files added"),
+            BuilderError::ImageTooLargeForFormat { size } => write!(
+                f,
+                "image too large: file data ends at {}, offsets are limited to {}",
+                size,
+                u32::MAX
+            ),
+            BuilderError::VersionDowngrade { version } => write!(
+                f,
+                "image has format version {}, newer than version {} written by this builder",
+                version,
+                simplefs::FORMAT_VERSION
+            ),
+            BuilderError::InvalidDirectoryAlignment { alignment } => {
+                write!(f, "directory alignment {} is not a power of two", alignment)
+            }
+            BuilderError::AlignmentTooLarge { alignment } => {
+                write!(f, "alignment {} has no power of two above it", alignment)
+            }
         }
     }
 }
@@ -25,10 +66,22 @@ impl std::error::Error for BuilderError {}
 #[derive(Debug, Default, Clone, PartialEq, Eq)]
 struct FileInfo {
     data: Vec<u8>,
+    content_type: Option<String>,
+    logical_length: Option<usize>,
+    // Image offset requested with add_file_at
+    offset: Option<usize>,
+    flags: u32,
+    align_log2: u8,
 }
 
 pub struct SimpleFsBuilder {
     capacity: usize,
+    build_time: u64,
+    // Format version of the image the files came from, see from_image
+    version: u16,
+    backup: bool,
+    dir_alignment: usize,
+    require_nonempty: bool,
     files: Vec<FileInfo>,
 }
 
@@ -36,59 +89,378 @@ impl SimpleFsBuilder {
     pub fn new(capacity: usize) -> Self {
         Self {
             capacity,
+            build_time: 0,
+            version: simplefs::FORMAT_VERSION,
+            backup: false,
+            dir_alignment: 1,
+            require_nonempty: false,
             files: Vec::new(),
         }
     }
 
+    // Start from the files of an existing image, for example to repack it with a
+    // different capacity. Files are laid out again from scratch, so fixed offsets and
+    // directory alignment of the original image are not kept. finalize fails with
+    // VersionDowngrade if the image is newer than the format this builder writes.
+    pub fn from_image<S: Storage>(fs: &FileSystem<S>) -> Result<Self, Error<S::Error>> {
+        let mut builder = Self::new(fs.storage().capacity());
+        builder.version = fs.version();
+        builder.set_build_time(fs.build_time());
+        builder.set_backup(fs.flags().has_backup_header());
+
+        for index in 0..fs.get_num_files() as usize {
+            let (_, direntry) = fs.open_with_entry(index)?;
+            let mut data = vec![0; direntry.length as usize];
+            fs.storage()
+                .read(direntry.offset as usize, &mut data)
+                .map_err(Error::Storage)?;
+
+            builder.files.push(FileInfo {
+                data,
+                content_type: direntry.content_type().map(str::to_owned),
+                logical_length: match direntry.logical_length {
+                    0 => None,
+                    logical_length => Some(logical_length as usize),
+                },
+                offset: None,
+                flags: direntry.flags,
+                align_log2: direntry.align_log2,
+            });
+        }
+
+        Ok(builder)
+    }
+
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    pub fn set_build_time(&mut self, build_time: u64) {
+        self.build_time = build_time;
+    }
+
+    // Pad the image to capacity and put a copy of the directory and header at its very
+    // end. Readers look for the copy at the end of storage, so the capacity must be the
+    // size of the partition the image is written to. The copy doesn't protect file data,
+    // its image_crc is left as 0.
+    pub fn set_backup(&mut self, backup: bool) {
+        self.backup = backup;
+    }
+
+    // Pad the image after the header so that the directory starts at a multiple of
+    // alignment bytes, which must be a power of two.
+    pub fn set_directory_alignment(&mut self, alignment: usize) -> Result<(), BuilderError> {
+        if !alignment.is_power_of_two() {
+            return Err(BuilderError::InvalidDirectoryAlignment { alignment });
+        }
+
+        self.dir_alignment = alignment;
+        Ok(())
+    }
+
+    // Make finalize fail with NoFiles instead of producing an empty image.
+    pub fn require_nonempty(&mut self) {
+        self.require_nonempty = true;
+    }
+
     pub fn add_file(&mut self, data: Vec<u8>) {
-        self.files.push(FileInfo { data })
+        self.files.push(FileInfo {
+            data,
+            content_type: None,
+            logical_length: None,
+            offset: None,
+            flags: 0,
+            align_log2: 0,
+        })
+    }
+
+    // Add a file stored at a fixed image offset, for layouts where a file must be at a
+    // known address. Other files are placed around it, gaps are zero-filled.
+    pub fn add_file_at(&mut self, offset: usize, data: Vec<u8>) {
+        self.files.push(FileInfo {
+            data,
+            content_type: None,
+            logical_length: None,
+            offset: Some(offset),
+            flags: 0,
+            align_log2: 0,
+        })
+    }
+
+    // Add a file that becomes index 0 in the image, ahead of files added before it.
+    // Some bootloaders expect the boot image to be the first file.
+    pub fn add_file_at_front(&mut self, data: Vec<u8>) {
+        self.files.insert(
+            0,
+            FileInfo {
+                data,
+                content_type: None,
+                logical_length: None,
+                offset: None,
+                flags: 0,
+                align_log2: 0,
+            },
+        )
+    }
+
+    pub fn add_file_with_content_type(&mut self, data: Vec<u8>, content_type: &str) {
+        self.files.push(FileInfo {
+            data,
+            content_type: Some(content_type.to_owned()),
+            logical_length: None,
+            offset: None,
+            flags: 0,
+            align_log2: 0,
+        })
+    }
+
+    // Add a file stored at an offset that is a multiple of alignment, rounded up to a
+    // power of two. Readers reject the file if it isn't aligned.
+    pub fn add_file_aligned(
+        &mut self,
+        data: Vec<u8>,
+        alignment: usize,
+    ) -> Result<(), BuilderError> {
+        let alignment = alignment
+            .max(1)
+            .checked_next_power_of_two()
+            .ok_or(BuilderError::AlignmentTooLarge { alignment })?;
+        self.files.push(FileInfo {
+            data,
+            content_type: None,
+            logical_length: None,
+            offset: None,
+            flags: 0,
+            // Less than usize::BITS, so it fits
+            align_log2: alignment.trailing_zeros() as u8,
+        });
+        Ok(())
+    }
+
+    // Add a file with flags stored verbatim in its directory entry, see File::flags
+    pub fn add_file_with_flags(&mut self, data: Vec<u8>, flags: u32) {
+        self.files.push(FileInfo {
+            data,
+            content_type: None,
+            logical_length: None,
+            offset: None,
+            flags,
+            align_log2: 0,
+        })
+    }
+
+    // Add a file that reads as data followed by zeros up to logical_length bytes.
+    // Only data is stored in the image.
+    pub fn add_sparse_file(&mut self, data: Vec<u8>, logical_length: usize) {
+        self.files.push(FileInfo {
+            data,
+            content_type: None,
+            logical_length: Some(logical_length),
+            offset: None,
+            flags: 0,
+            align_log2: 0,
+        })
     }
 
     pub fn finalize(self) -> Result<Bytes, BuilderError> {
+        // Writing an older format would drop the fields the newer one added
+        if self.version > simplefs::FORMAT_VERSION {
+            return Err(BuilderError::VersionDowngrade {
+                version: self.version,
+            });
+        }
+        if self.require_nonempty && self.files.is_empty() {
+            return Err(BuilderError::NoFiles);
+        }
+
         let num_files = self
             .files
             .len()
             .try_into()
             .map_err(|_| BuilderError::TooManyFiles)?;
 
-        let total_file_size: usize = self.files.iter().map(|file| file.data.len()).sum();
         let dir_size = self.files.len() * size_of::<DirEntry>();
+        // Doesn't overflow, the alignment is a power of two
+        let dir_start = size_of::<FilesystemHeader>().next_multiple_of(self.dir_alignment);
+        let dir_end = dir_start
+            .checked_add(dir_size)
+            .ok_or(BuilderError::ImageTooLargeForFormat { size: dir_start })?;
+        let regions = self.layout(dir_end)?;
+        let data_end = regions
+            .iter()
+            .map(|region| region.end)
+            .fold(dir_end, usize::max);
+        let backup_size = if self.backup {
+            size_of::<FilesystemHeader>() + dir_size
+        } else {
+            0
+        };
+        let needed = data_end
+            .checked_add(backup_size)
+            .ok_or(BuilderError::ImageTooLargeForFormat { size: data_end })?;
+        if needed > self.capacity {
+            return Err(BuilderError::OutOfSpace {
+                needed,
+                capacity: self.capacity,
+            });
+        }
+        // Offsets are u32 in the directory, even if capacity allows more
+        if data_end > u32::MAX as usize {
+            return Err(BuilderError::ImageTooLargeForFormat { size: data_end });
+        }
+        let image_size = if self.backup { self.capacity } else { data_end };
 
-        let mut writer =
-            BytesMut::with_capacity(size_of::<FilesystemHeader>() + dir_size + total_file_size);
+        let mut writer = BytesMut::with_capacity(image_size);
 
-        FilesystemHeader {
+        let mut flags = HeaderFlags::default();
+        if self.backup {
+            flags = flags.with(HeaderFlags::BACKUP_HEADER);
+        }
+
+        let mut header = FilesystemHeader {
             signature: simplefs::SIGNATURE,
+            version: simplefs::FORMAT_VERSION,
             num_files,
-        }
-        .to_bytes(&mut writer);
+            dir_entry_size: size_of::<DirEntry>() as u16,
+            image_crc: 0,
+            build_time: self.build_time,
+            header_crc: 0,
+            flags: flags.bits(),
+            dir_offset: dir_start
+                .try_into()
+                .map_err(|_| BuilderError::ImageTooLargeForFormat { size: data_end })?,
+            dir_crc: 0,
+            largest_file: 0,
+            image_size: image_size
+                .try_into()
+                .map_err(|_| BuilderError::ImageTooLargeForFormat { size: image_size })?,
+        };
+        // Rewritten once the directory CRC is known
+        header.to_bytes(&mut writer);
+        writer.put_bytes(0, dir_start - size_of::<FilesystemHeader>());
 
-        let mut current_offset = size_of::<FilesystemHeader>() + dir_size;
+        for (file, region) in self.files.iter().zip(&regions) {
+            let mut content_type = [0; DirEntry::CONTENT_TYPE_SIZE];
+            if let Some(file_content_type) = &file.content_type {
+                content_type
+                    .get_mut(..file_content_type.len())
+                    .ok_or(BuilderError::ContentTypeTooLong)?
+                    .copy_from_slice(file_content_type.as_bytes());
+            }
 
-        for file in &self.files {
             let direntry = DirEntry {
-                offset: current_offset
+                offset: region
+                    .start
                     .try_into()
-                    .map_err(|_| BuilderError::OutOfSpace)?,
+                    .map_err(|_| BuilderError::ImageTooLargeForFormat { size: data_end })?,
                 length: file
                     .data
                     .len()
                     .try_into()
-                    .map_err(|_| BuilderError::FileTooBig)?,
+                    .map_err(|_| BuilderError::FileTooBig {
+                        size: file.data.len(),
+                        max: u32::MAX as u64,
+                    })?,
+                content_type,
+                logical_length: match file.logical_length {
+                    None => 0,
+                    Some(logical_length) if logical_length < file.data.len() => {
+                        return Err(BuilderError::LogicalLengthTooShort)
+                    }
+                    Some(logical_length) => {
+                        logical_length
+                            .try_into()
+                            .map_err(|_| BuilderError::FileTooBig {
+                                size: logical_length,
+                                max: u32::MAX as u64,
+                            })?
+                    }
+                },
+                flags: file.flags,
+                align_log2: file.align_log2,
             };
-
-            current_offset += file.data.len();
-            if current_offset > self.capacity {
-                return Err(BuilderError::OutOfSpace);
-            }
+            header.largest_file = header.largest_file.max(direntry.logical_size());
 
             direntry.to_bytes(&mut writer);
         }
 
-        for file in &self.files {
-            writer.put_slice(file.data.as_slice());
+        let mut dir_crc = Crc32::new();
+        dir_crc.update(&writer[dir_start..]);
+        header.dir_crc = dir_crc.finalize();
+        header.header_crc = header.crc();
+        header.to_bytes(&mut &mut writer[..size_of::<FilesystemHeader>()]);
+
+        writer.resize(data_end, 0);
+        for (file, region) in self.files.iter().zip(&regions) {
+            writer[region.clone()].copy_from_slice(&file.data);
+        }
+
+        if self.backup {
+            writer.resize(image_size - backup_size, 0);
+            let directory = writer[dir_start..][..dir_size].to_vec();
+            writer.put_slice(&directory);
+            header.to_bytes(&mut writer);
         }
 
+        header.image_crc = simplefs::image_crc(&writer);
+        header.to_bytes(&mut &mut writer[..size_of::<FilesystemHeader>()]);
+
         Ok(writer.freeze())
     }
+
+    // Image ranges of file data. Files added with add_file_at go where requested, the
+    // rest are packed in order from data_start, skipping over the fixed ones.
+    fn layout(&self, data_start: usize) -> Result<Vec<Range<usize>>, BuilderError> {
+        let mut fixed: Vec<Range<usize>> = Vec::new();
+        for file in &self.files {
+            if let Some(offset) = file.offset {
+                let region = checked_region(offset, file.data.len())?;
+                let overlaps = fixed
+                    .iter()
+                    .any(|other| region.start < other.end && other.start < region.end);
+                if offset < data_start || overlaps {
+                    return Err(BuilderError::OverlappingFile { offset });
+                }
+                fixed.push(region);
+            }
+        }
+
+        let mut current_offset = data_start;
+        self.files
+            .iter()
+            .map(|file| {
+                let len = file.data.len();
+                if let Some(offset) = file.offset {
+                    return checked_region(offset, len);
+                }
+
+                let alignment = 1usize << file.align_log2;
+                let mut region = checked_region(align_up(current_offset, alignment)?, len)?;
+                while let Some(other) = fixed
+                    .iter()
+                    .find(|other| region.start < other.end && other.start < region.end)
+                {
+                    region = checked_region(align_up(other.end, alignment)?, len)?;
+                }
+
+                current_offset = region.end;
+                Ok(region)
+            })
+            .collect()
+    }
+}
+
+// Range of len bytes at offset, failing instead of wrapping around
+fn checked_region(offset: usize, len: usize) -> Result<Range<usize>, BuilderError> {
+    let end = offset
+        .checked_add(len)
+        .ok_or(BuilderError::ImageTooLargeForFormat { size: offset })?;
+    Ok(offset..end)
+}
+
+fn align_up(offset: usize, alignment: usize) -> Result<usize, BuilderError> {
+    offset
+        .checked_next_multiple_of(alignment)
+        .ok_or(BuilderError::ImageTooLargeForFormat { size: offset })
 }
@@ -0,0 +1,64 @@
+use simplefs::{Error, File, FileSystem, Storage};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffEntry {
+    Added(usize),
+    Removed(usize),
+    Modified(usize),
+}
+
+impl std::fmt::Display for DiffEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DiffEntry::Added(index) => write!(f, "added file {index}"),
+            DiffEntry::Removed(index) => write!(f, "removed file {index}"),
+            DiffEntry::Modified(index) => write!(f, "modified file {index}"),
+        }
+    }
+}
+
+// Compare two filesystems. Files are matched by index and compared by contents.
+pub fn diff<S: Storage>(
+    fs_a: &FileSystem<S>,
+    fs_b: &FileSystem<S>,
+) -> Result<Vec<DiffEntry>, Error<S::Error>> {
+    let num_files_a = fs_a.get_num_files() as usize;
+    let num_files_b = fs_b.get_num_files() as usize;
+
+    let mut entries = Vec::new();
+    for index in 0..num_files_a.max(num_files_b) {
+        if index >= num_files_b {
+            entries.push(DiffEntry::Removed(index));
+        } else if index >= num_files_a {
+            entries.push(DiffEntry::Added(index));
+        } else if !same_contents(&mut fs_a.open(index)?, &mut fs_b.open(index)?)? {
+            entries.push(DiffEntry::Modified(index));
+        }
+    }
+
+    Ok(entries)
+}
+
+fn same_contents<S: Storage>(
+    file_a: &mut File<S>,
+    file_b: &mut File<S>,
+) -> Result<bool, Error<S::Error>> {
+    if file_a.total_size() != file_b.total_size() {
+        return Ok(false);
+    }
+
+    let mut buf_a = [0; 512];
+    let mut buf_b = [0; 512];
+    loop {
+        let len = file_a.read(&mut buf_a)?;
+        if len == 0 {
+            return Ok(true);
+        }
+
+        // Files have the same size, so the second read returns the same length
+        file_b.read(&mut buf_b[..len])?;
+        if buf_a[..len] != buf_b[..len] {
+            return Ok(false);
+        }
+    }
+}
@@ -1,12 +1,19 @@
 use crate::builder::SimpleFsBuilder;
+use crate::diff::diff;
+use crate::manifest::{manifest, parse_manifest, verify};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use clap::Parser;
+use simplefs::FileSystem;
 use std::fs::File;
 use std::io::Read;
 use std::io::Write;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 mod builder;
+mod diff;
+mod manifest;
 
 #[cfg(test)]
 mod tests;
@@ -16,34 +23,253 @@ mod tests;
 #[command(about)]
 struct Args {
     /// Image file name
-    #[arg(short)]
-    output: std::path::PathBuf,
+    #[arg(short, required_unless_present = "verify")]
+    output: Option<std::path::PathBuf>,
     files: Vec<std::path::PathBuf>,
     /// Max image size
     #[arg(short, long, default_value_t = 4*1024*1024)]
     capacity: usize,
+    /// Image build time in seconds since Unix epoch, defaults to current time
+    #[arg(long)]
+    build_time: Option<u64>,
+    /// List files changed compared to an existing image
+    #[arg(long)]
+    diff: Option<std::path::PathBuf>,
+    /// Write a JSON list of files with their offsets, lengths and CRCs
+    #[arg(long)]
+    manifest: Option<std::path::PathBuf>,
+    /// Check the lengths and CRCs of files in an existing image against the manifest
+    /// given with --manifest, instead of building an image
+    #[arg(
+        long,
+        requires = "manifest",
+        conflicts_with_all = [
+            "output",
+            "files",
+            "capacity",
+            "build_time",
+            "diff",
+            "backup",
+            "first",
+            "sparse",
+            "at",
+            "with_flags",
+            "aligned",
+            "repack",
+            "dir_align",
+            "require_nonempty",
+        ]
+    )]
+    verify: Option<std::path::PathBuf>,
+    /// Pad the image to capacity and end it with a backup copy of the header and directory
+    #[arg(long)]
+    backup: bool,
+    /// File to store at index 0, ahead of the other files, e.g. for bootloaders
+    #[arg(long)]
+    first: Option<std::path::PathBuf>,
+    /// Sparse file added after the listed ones, reading as its contents followed by
+    /// zeros up to LENGTH bytes. Can be repeated
+    #[arg(long, value_name = "FILE:LENGTH")]
+    sparse: Vec<String>,
+    /// File added after the listed ones at a fixed image offset, e.g. 0x1000 for a
+    /// bootloader. Can be repeated
+    #[arg(long, value_name = "FILE:OFFSET")]
+    at: Vec<String>,
+    /// File added after the listed ones with FLAGS stored in its directory entry. Can be
+    /// repeated
+    #[arg(long, value_name = "FILE:FLAGS")]
+    with_flags: Vec<String>,
+    /// File added after the listed ones at an offset that is a multiple of ALIGNMENT,
+    /// e.g. for DMA. Can be repeated
+    #[arg(long, value_name = "FILE:ALIGNMENT")]
+    aligned: Vec<String>,
+    /// Existing image whose files are added ahead of the listed ones. Its build time and
+    /// backup are kept unless overridden
+    #[arg(long)]
+    repack: Option<std::path::PathBuf>,
+    /// Start the directory at a multiple of this many bytes, a power of two
+    #[arg(long, default_value_t = 1)]
+    dir_align: usize,
+    /// Fail instead of writing an image without files
+    #[arg(long)]
+    require_nonempty: bool,
+}
+
+// Content type of common web assets, by file extension
+fn content_type(filename: &Path) -> Option<&'static str> {
+    let extension = filename.extension()?.to_str()?.to_ascii_lowercase();
+    match extension.as_str() {
+        "html" | "htm" => Some("text/html"),
+        "css" => Some("text/css"),
+        "js" => Some("text/javascript"),
+        "json" => Some("application/json"),
+        "txt" => Some("text/plain"),
+        "png" => Some("image/png"),
+        "jpg" | "jpeg" => Some("image/jpeg"),
+        "gif" => Some("image/gif"),
+        "svg" => Some("image/svg+xml"),
+        "ico" => Some("image/x-icon"),
+        "wasm" => Some("application/wasm"),
+        _ => None,
+    }
+}
+
+// Split a FILE:NUMBER argument. The number may be in hex with a 0x prefix.
+fn parse_file_number(arg: &str) -> Result<(&str, u64)> {
+    let (filename, number) = arg
+        .rsplit_once(':')
+        .ok_or_else(|| anyhow!("expected FILE:NUMBER, got {}", arg))?;
+    let number = match number.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16)?,
+        None => number.parse()?,
+    };
+    Ok((filename, number))
+}
+
+fn mount_image(image: &[u8]) -> Result<FileSystem<&[u8]>> {
+    FileSystem::mount(image).map_err(|e| anyhow!("invalid image: {:?}", e))
+}
+
+fn verify_image(filename: &Path, manifest_filename: &Path) -> Result<()> {
+    let image = std::fs::read(filename)?;
+    let fs = mount_image(&image)?;
+    let entries = parse_manifest(&std::fs::read_to_string(manifest_filename)?)?;
+
+    let mismatches = verify(&fs, &entries).map_err(|e| anyhow!("{:?}", e))?;
+    for mismatch in &mismatches {
+        println!("{}", mismatch);
+    }
+    if !mismatches.is_empty() {
+        return Err(anyhow!(
+            "{} doesn't match {}",
+            filename.display(),
+            manifest_filename.display()
+        ));
+    }
+
+    println!(
+        "{} matches {}",
+        filename.display(),
+        manifest_filename.display()
+    );
+    Ok(())
 }
 
 fn main() -> Result<()> {
     let args = Args::parse();
 
-    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(args.capacity);
+    if let Some(filename) = &args.verify {
+        let manifest_filename = args
+            .manifest
+            .as_ref()
+            .ok_or_else(|| anyhow!("--verify needs --manifest"))?;
+        return verify_image(filename, manifest_filename);
+    }
+
+    let (mut builder, mut names) = match &args.repack {
+        Some(filename) => {
+            println!("Repacking image {}", filename.display());
+            let image = std::fs::read(filename)?;
+            let fs = mount_image(&image)?;
+            let builder = SimpleFsBuilder::from_image(&fs)
+                .map_err(|e| anyhow!("{:?}", e))?
+                .with_capacity(args.capacity);
+            let names = (0..fs.get_num_files())
+                .map(|index| format!("{}[{}]", filename.display(), index))
+                .collect();
+            (builder, names)
+        }
+        None => (SimpleFsBuilder::new(args.capacity), Vec::new()),
+    };
+
+    match args.build_time {
+        Some(build_time) => builder.set_build_time(build_time),
+        None if args.repack.is_none() => {
+            builder.set_build_time(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+        }
+        None => {}
+    }
+    if args.backup {
+        builder.set_backup(true);
+    }
+    builder.set_directory_alignment(args.dir_align)?;
+    if args.require_nonempty {
+        builder.require_nonempty();
+    }
+
     for filename in args.files {
         println!("Adding file {}", filename.display());
-        let mut f = File::open(filename)?;
+        let mut f = File::open(&filename)?;
         let mut data = Vec::new();
         f.read_to_end(&mut data)?;
-        builder.add_file(data);
+
+        match content_type(&filename) {
+            Some(content_type) => builder.add_file_with_content_type(data, content_type),
+            None => builder.add_file(data),
+        }
+        names.push(filename.display().to_string());
+    }
+
+    for sparse in &args.sparse {
+        let (filename, logical_length) = parse_file_number(sparse)?;
+        println!("Adding sparse file {}, {} bytes", filename, logical_length);
+        builder.add_sparse_file(std::fs::read(filename)?, logical_length.try_into()?);
+        names.push(filename.to_string());
+    }
+
+    for with_flags in &args.with_flags {
+        let (filename, flags) = parse_file_number(with_flags)?;
+        println!("Adding file {} with flags {:#x}", filename, flags);
+        builder.add_file_with_flags(std::fs::read(filename)?, flags.try_into()?);
+        names.push(filename.to_string());
+    }
+
+    for aligned in &args.aligned {
+        let (filename, alignment) = parse_file_number(aligned)?;
+        println!("Adding file {} aligned to {} bytes", filename, alignment);
+        builder.add_file_aligned(std::fs::read(filename)?, alignment.try_into()?)?;
+        names.push(filename.to_string());
+    }
+
+    for at in &args.at {
+        let (filename, offset) = parse_file_number(at)?;
+        println!("Adding file {} at offset {:#x}", filename, offset);
+        builder.add_file_at(offset.try_into()?, std::fs::read(filename)?);
+        names.push(filename.to_string());
+    }
+
+    if let Some(filename) = args.first {
+        println!("Adding file {} at index 0", filename.display());
+        builder.add_file_at_front(std::fs::read(&filename)?);
+        names.insert(0, filename.display().to_string());
     }
 
     let bytes = builder.finalize()?;
 
+    if let Some(filename) = args.diff {
+        let old_image = std::fs::read(filename)?;
+        let old_fs = mount_image(&old_image)?;
+        let new_fs = mount_image(&bytes)?;
+
+        for entry in diff(&old_fs, &new_fs).map_err(|e| anyhow!("{:?}", e))? {
+            println!("{}", entry);
+        }
+    }
+
+    if let Some(filename) = args.manifest {
+        let fs = mount_image(&bytes)?;
+        let json = manifest(&fs, &names).map_err(|e| anyhow!("{:?}", e))?;
+        println!("Writing manifest to {}", filename.display());
+        std::fs::write(filename, json)?;
+    }
+
+    let output = args.output.ok_or_else(|| anyhow!("no image file name"))?;
     println!(
         "Writing image to {}, size {} bytes",
-        args.output.display(),
+        output.display(),
         bytes.len()
     );
-    let mut image_file = File::create(args.output)?;
+    let mut image_file = File::create(output)?;
     image_file.write_all(&bytes)?;
 
     Ok(())
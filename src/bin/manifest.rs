@@ -0,0 +1,305 @@
+use std::fmt::Write;
+use std::str::FromStr;
+
+use simplefs::{Crc32, Error, File, FileSystem, Storage};
+
+// File description read back from a manifest
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    pub index: usize,
+    pub name: String,
+    pub length: usize,
+    pub crc: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseError {
+    pub position: usize,
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid manifest at byte {}", self.position)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mismatch {
+    Missing { index: usize, name: String },
+    Length { index: usize, name: String },
+    Crc { index: usize, name: String },
+    Unlisted(usize),
+}
+
+impl std::fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Mismatch::Missing { index, name } => write!(f, "missing file {index} ({name})"),
+            Mismatch::Length { index, name } => write!(f, "length of file {index} ({name})"),
+            Mismatch::Crc { index, name } => write!(f, "contents of file {index} ({name})"),
+            Mismatch::Unlisted(index) => write!(f, "file {index} not in manifest"),
+        }
+    }
+}
+
+// JSON description of every file in the image: index, source name, data offset,
+// length and CRC32 of the contents. names are matched to files by index.
+pub fn manifest<S: Storage>(
+    fs: &FileSystem<S>,
+    names: &[String],
+) -> Result<String, Error<S::Error>> {
+    let mut json = String::from("[");
+
+    for index in 0..fs.get_num_files() as usize {
+        let direntry = fs.read_dir_entry(index)?;
+        let mut file = fs.open(index)?;
+        let crc = file_crc(&mut file)?;
+
+        if index > 0 {
+            json.push(',');
+        }
+        json.push_str("\n  {\"index\": ");
+        write!(json, "{}", index).unwrap();
+        json.push_str(", \"name\": ");
+        write_string(&mut json, names.get(index).map_or("", String::as_str));
+        write!(
+            json,
+            ", \"offset\": {}, \"length\": {}, \"crc\": {}}}",
+            { direntry.offset },
+            file.total_size(),
+            crc
+        )
+        .unwrap();
+    }
+
+    json.push_str("\n]\n");
+    Ok(json)
+}
+
+// Check the files in the image against a manifest written by manifest(). Files are
+// matched by index and compared by length and CRC, names only label the mismatches.
+pub fn verify<S: Storage>(
+    fs: &FileSystem<S>,
+    entries: &[ManifestEntry],
+) -> Result<Vec<Mismatch>, Error<S::Error>> {
+    let num_files = fs.get_num_files() as usize;
+    let mut listed = vec![false; num_files];
+
+    let mut mismatches = Vec::new();
+    for entry in entries {
+        let (index, name) = (entry.index, entry.name.clone());
+        if index >= num_files {
+            mismatches.push(Mismatch::Missing { index, name });
+            continue;
+        }
+        listed[index] = true;
+
+        let mut file = fs.open(index)?;
+        if file.total_size() != entry.length {
+            mismatches.push(Mismatch::Length { index, name });
+        } else if file_crc(&mut file)? != entry.crc {
+            mismatches.push(Mismatch::Crc { index, name });
+        }
+    }
+
+    for (index, _) in listed.iter().enumerate().filter(|(_, &listed)| !listed) {
+        mismatches.push(Mismatch::Unlisted(index));
+    }
+
+    Ok(mismatches)
+}
+
+// Read back a manifest written by manifest(). Only accepts the subset of JSON it
+// produces: an array of objects with string and unsigned integer values.
+pub fn parse_manifest(json: &str) -> Result<Vec<ManifestEntry>, ParseError> {
+    let mut reader = Reader { json, position: 0 };
+    let mut entries = Vec::new();
+
+    reader.expect(b'[')?;
+    if !reader.consume(b']') {
+        loop {
+            entries.push(reader.entry()?);
+            if reader.consume(b']') {
+                break;
+            }
+            reader.expect(b',')?;
+        }
+    }
+
+    reader.skip_whitespace();
+    if reader.position != json.len() {
+        return Err(reader.error());
+    }
+
+    Ok(entries)
+}
+
+// CRC32 of the rest of the file
+fn file_crc<S: Storage>(file: &mut File<S>) -> Result<u32, Error<S::Error>> {
+    let mut crc = Crc32::new();
+    let mut buf = [0; 512];
+    loop {
+        let len = file.read(&mut buf)?;
+        if len == 0 {
+            return Ok(crc.finalize());
+        }
+        crc.update(&buf[..len]);
+    }
+}
+
+fn write_string(json: &mut String, value: &str) {
+    json.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c.is_control() => write!(json, "\\u{:04x}", c as u32).unwrap(),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+}
+
+struct Reader<'a> {
+    json: &'a str,
+    position: usize,
+}
+
+impl Reader<'_> {
+    fn entry(&mut self) -> Result<ManifestEntry, ParseError> {
+        let start = self.error();
+        let (mut index, mut name, mut length, mut crc) = (None, None, None, None);
+
+        self.expect(b'{')?;
+        loop {
+            let key_error = self.error();
+            let key = self.string()?;
+            self.expect(b':')?;
+            match key.as_str() {
+                "index" => index = Some(self.number()?),
+                "name" => name = Some(self.string()?),
+                // Files are matched by index, their offsets may change between builds
+                "offset" => {
+                    self.number::<u32>()?;
+                }
+                "length" => length = Some(self.number()?),
+                "crc" => crc = Some(self.number()?),
+                _ => return Err(key_error),
+            }
+
+            if self.consume(b'}') {
+                break;
+            }
+            self.expect(b',')?;
+        }
+
+        match (index, name, length, crc) {
+            (Some(index), Some(name), Some(length), Some(crc)) => Ok(ManifestEntry {
+                index,
+                name,
+                length,
+                crc,
+            }),
+            _ => Err(start),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, ParseError> {
+        self.expect(b'"')?;
+
+        let mut value = String::new();
+        loop {
+            match self.next_char()? {
+                '"' => return Ok(value),
+                '\\' => {
+                    let escaped = match self.next_char()? {
+                        '"' => '"',
+                        '\\' => '\\',
+                        '/' => '/',
+                        'n' => '\n',
+                        'r' => '\r',
+                        't' => '\t',
+                        'u' => self.unicode_escape()?,
+                        _ => return Err(self.error()),
+                    };
+                    value.push(escaped);
+                }
+                c => value.push(c),
+            }
+        }
+    }
+
+    // Value of \uXXXX, after the u
+    fn unicode_escape(&mut self) -> Result<char, ParseError> {
+        let digits = self
+            .json
+            .get(self.position..self.position + 4)
+            .filter(|digits| digits.bytes().all(|c| c.is_ascii_hexdigit()))
+            .ok_or(self.error())?;
+        let c = u32::from_str_radix(digits, 16)
+            .ok()
+            .and_then(char::from_u32)
+            .ok_or(self.error())?;
+        self.position += 4;
+        Ok(c)
+    }
+
+    fn number<T: FromStr>(&mut self) -> Result<T, ParseError> {
+        self.skip_whitespace();
+        let start = self.error();
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.position += 1;
+        }
+        self.json[start.position..self.position]
+            .parse()
+            .map_err(|_| start)
+    }
+
+    fn next_char(&mut self) -> Result<char, ParseError> {
+        let c = self.json[self.position..]
+            .chars()
+            .next()
+            .ok_or(self.error())?;
+        self.position += c.len_utf8();
+        Ok(c)
+    }
+
+    fn expect(&mut self, c: u8) -> Result<(), ParseError> {
+        if self.consume(c) {
+            Ok(())
+        } else {
+            Err(self.error())
+        }
+    }
+
+    // Skip whitespace, then c if it comes next
+    fn consume(&mut self, c: u8) -> bool {
+        self.skip_whitespace();
+        if self.peek() == Some(c) {
+            self.position += 1;
+            return true;
+        }
+        false
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(|c| c.is_ascii_whitespace()) {
+            self.position += 1;
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.json.as_bytes().get(self.position).copied()
+    }
+
+    fn error(&self) -> ParseError {
+        ParseError {
+            position: self.position,
+        }
+    }
+}
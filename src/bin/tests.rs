@@ -1,9 +1,13 @@
-use crate::builder::SimpleFsBuilder;
+use crate::builder::{BuilderError, SimpleFsBuilder};
+use crate::diff::{diff, DiffEntry};
+use crate::manifest::{manifest, parse_manifest, verify, ManifestEntry, Mismatch, ParseError};
 use simplefs::*;
 
+use std::cell::RefCell;
 use std::mem::size_of;
+use std::ops::Range;
 
-use bytes::Bytes;
+use bytes::{BufMut, Bytes, BytesMut};
 use quickcheck::{quickcheck, Arbitrary, Gen};
 
 const CAPACITY: usize = 4096 * 128;
@@ -40,6 +44,48 @@ impl Storage for RamStorage {
     }
 }
 
+// Records every storage access
+#[derive(Debug)]
+struct RecordingStorage {
+    inner: RamStorage,
+    reads: RefCell<Vec<Range<usize>>>,
+}
+
+impl RecordingStorage {
+    fn new(bytes: Bytes) -> Self {
+        Self {
+            inner: RamStorage::new(bytes),
+            reads: RefCell::new(Vec::new()),
+        }
+    }
+}
+
+impl Storage for RecordingStorage {
+    type Error = RamStorageError;
+
+    fn read(&self, off: usize, buf: &mut [u8]) -> Result<(), Self::Error> {
+        self.reads.borrow_mut().push(off..off + buf.len());
+        self.inner.read(off, buf)
+    }
+
+    fn capacity(&self) -> usize {
+        self.inner.capacity()
+    }
+}
+
+// For checking reads made by a mount that fails
+impl Storage for &RecordingStorage {
+    type Error = RamStorageError;
+
+    fn read(&self, off: usize, buf: &mut [u8]) -> Result<(), Self::Error> {
+        (*self).read(off, buf)
+    }
+
+    fn capacity(&self) -> usize {
+        (*self).capacity()
+    }
+}
+
 fn read_full_file(fs: &FileSystem<RamStorage>, index: usize) -> Vec<u8> {
     let mut file = fs.open(index).expect("file open");
     let mut buf = Vec::new();
@@ -50,6 +96,18 @@ fn read_full_file(fs: &FileSystem<RamStorage>, index: usize) -> Vec<u8> {
     return buf;
 }
 
+fn try_read_full_file(
+    fs: &FileSystem<RamStorage>,
+    index: usize,
+) -> Result<Vec<u8>, Error<RamStorageError>> {
+    let mut file = fs.open(index)?;
+    let mut buf = vec![0; file.total_size()];
+
+    let bytes_read = file.read(&mut buf)?;
+    buf.truncate(bytes_read);
+    Ok(buf)
+}
+
 #[test]
 fn test_empty_fs_build() {
     let builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
@@ -65,6 +123,7 @@ fn test_empty_fs_build() {
 
     let fs = FileSystem::mount(RamStorage::new(image_bytes)).expect("filesystem mount");
     assert_eq!(fs.get_num_files(), 0);
+    assert!(fs.is_empty());
     let status = fs.open(0).expect_err("open non-existent file");
     assert_eq!(status, Error::InvalidFileIndex);
 }
@@ -92,10 +151,645 @@ fn test_single_file_fs_build() {
 
     let fs = FileSystem::mount(RamStorage::new(image_bytes)).expect("filesystem mount");
     assert_eq!(fs.get_num_files(), 1);
+    assert!(!fs.is_empty());
     let buf = read_full_file(&fs, 0);
     assert_eq!(filedata, buf);
 }
 
+#[test]
+fn test_storage_round_trip() {
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.add_file(vec![1, 2, 3]);
+
+    let image_bytes = builder.finalize().expect("fs image");
+
+    let fs = FileSystem::mount(RamStorage::new(image_bytes.clone())).expect("filesystem mount");
+    assert_eq!(fs.storage().bytes, image_bytes);
+
+    let storage = fs.into_storage();
+    assert_eq!(storage.bytes, image_bytes);
+
+    let fs = FileSystem::mount(storage).expect("filesystem remount");
+    assert_eq!(fs.get_num_files(), 1);
+    assert_eq!(read_full_file(&fs, 0), vec![1, 2, 3]);
+}
+
+#[test]
+fn test_extended_dir_entries() {
+    // Image written with entries carrying an extra field unknown to this reader
+    let dir_entry_size = size_of::<DirEntry>() + 4;
+    let files = [vec![1, 2, 3], vec![4, 5, 6, 7]];
+
+    let mut content_type = [0; DirEntry::CONTENT_TYPE_SIZE];
+    content_type[..10].copy_from_slice(b"text/plain");
+
+    let mut directory = BytesMut::new();
+    let mut offset = size_of::<FilesystemHeader>() + files.len() * dir_entry_size;
+    for data in &files {
+        DirEntry {
+            offset: offset as u32,
+            length: data.len() as u32,
+            content_type,
+            logical_length: 0,
+            flags: 0,
+            align_log2: 0,
+        }
+        .to_bytes(&mut directory);
+        directory.put_u32(0xdeadbeef);
+        offset += data.len();
+    }
+
+    let mut dir_crc = Crc32::new();
+    dir_crc.update(&directory);
+    let mut header = FilesystemHeader {
+        signature: simplefs::SIGNATURE,
+        version: FORMAT_VERSION,
+        num_files: files.len() as u16,
+        dir_entry_size: dir_entry_size as u16,
+        image_crc: 0,
+        build_time: 0,
+        header_crc: 0,
+        flags: 0,
+        dir_offset: 0,
+        dir_crc: dir_crc.finalize(),
+        largest_file: 0,
+        image_size: offset as u32,
+    };
+    header.header_crc = header.crc();
+
+    let mut writer = BytesMut::new();
+    header.to_bytes(&mut writer);
+    writer.put_slice(&directory);
+    for data in &files {
+        writer.put_slice(data);
+    }
+
+    let fs = FileSystem::mount(RamStorage::new(writer.freeze())).expect("filesystem mount");
+    assert_eq!(fs.get_num_files(), 2);
+    assert_eq!(fs.directory_size(), files.len() * dir_entry_size);
+    assert_eq!(read_full_file(&fs, 0), files[0]);
+    assert_eq!(read_full_file(&fs, 1), files[1]);
+    assert_eq!(
+        fs.open(1).expect("file open").content_type(),
+        Some("text/plain")
+    );
+}
+
+#[test]
+fn test_intermediate_dir_entries() {
+    // Entries too short for some field, e.g. from the builder with a 16-byte content
+    // type. The field and everything after it read as zero, not as the leftover bytes.
+    for dir_entry_size in [24, 40] {
+        let data_start = size_of::<FilesystemHeader>() + dir_entry_size;
+        let mut directory = BytesMut::new();
+        directory.put_u32(data_start as u32);
+        directory.put_u32(3);
+        directory.put_bytes(b't', dir_entry_size - DirEntry::MIN_SIZE);
+
+        let mut dir_crc = Crc32::new();
+        dir_crc.update(&directory);
+        let mut header = FilesystemHeader {
+            signature: simplefs::SIGNATURE,
+            version: FORMAT_VERSION,
+            num_files: 1,
+            dir_entry_size: dir_entry_size as u16,
+            image_crc: 0,
+            build_time: 0,
+            header_crc: 0,
+            flags: 0,
+            dir_offset: 0,
+            dir_crc: dir_crc.finalize(),
+            largest_file: 3,
+            image_size: data_start as u32 + 3,
+        };
+        header.header_crc = header.crc();
+
+        let mut writer = BytesMut::new();
+        header.to_bytes(&mut writer);
+        writer.put_slice(&directory);
+        writer.put_slice(&[1, 2, 3]);
+
+        let fs = FileSystem::mount_eager(
+            RamStorage::new(writer.freeze()),
+            &mut [DirEntry::default(); 8],
+        )
+        .expect("filesystem mount");
+        let direntry = fs.read_dir_entry(0).expect("dir entry");
+        assert_eq!(direntry.logical_size(), 3);
+        assert_eq!({ direntry.flags }, 0);
+        assert_eq!(direntry.alignment(), Some(1));
+
+        let file = fs.open(0).expect("file open");
+        assert_eq!(file.total_size(), 3);
+        assert_eq!(file.flags(), 0);
+        assert_eq!(read_full_file(&fs, 0), vec![1, 2, 3]);
+    }
+}
+
+#[test]
+fn test_short_dir_entries() {
+    let mut writer = BytesMut::new();
+    let mut header = FilesystemHeader {
+        signature: simplefs::SIGNATURE,
+        version: FORMAT_VERSION,
+        num_files: 1,
+        dir_entry_size: DirEntry::MIN_SIZE as u16 - 1,
+        image_crc: 0,
+        build_time: 0,
+        header_crc: 0,
+        flags: 0,
+        dir_offset: 0,
+        dir_crc: 0,
+        largest_file: 0,
+        image_size: 0,
+    };
+    header.header_crc = header.crc();
+    header.to_bytes(&mut writer);
+    writer.put_slice(&[0; 64]);
+
+    let status = FileSystem::mount(RamStorage::new(writer.freeze())).err();
+    assert_eq!(status, Some(Error::CorruptedFileSystem));
+}
+
+#[test]
+fn test_open_all() {
+    let files = vec![vec![1, 2, 3], vec![], vec![4; 100]];
+
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    for data in &files {
+        builder.add_file(data.clone());
+    }
+
+    let image_bytes = builder.finalize().expect("fs image");
+    let fs = FileSystem::mount(RamStorage::new(image_bytes)).expect("filesystem mount");
+
+    let opened = fs.open_all().expect("open all files");
+    assert_eq!(opened.len(), files.len());
+    for (mut file, data) in opened.into_iter().zip(&files) {
+        let mut buf = vec![0; file.total_size()];
+        assert_eq!(file.read(&mut buf), Ok(data.len()));
+        assert_eq!(&buf, data);
+    }
+}
+
+#[test]
+fn test_file_clone() {
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.add_file((0..10).collect());
+
+    let image_bytes = builder.finalize().expect("fs image");
+    let fs = FileSystem::mount(RamStorage::new(image_bytes)).expect("filesystem mount");
+
+    let mut file = fs.open(0).expect("file open");
+    let mut buf = [0; 4];
+    assert_eq!(file.read(&mut buf), Ok(4));
+    assert_eq!(buf, [0, 1, 2, 3]);
+
+    let mut clone = file.clone();
+    let mut clone_buf = [0; 8];
+    assert_eq!(clone.read(&mut clone_buf), Ok(6));
+    assert_eq!(clone_buf[..6], [4, 5, 6, 7, 8, 9]);
+    assert_eq!(clone.read(&mut clone_buf), Ok(0));
+
+    // Reading from the clone didn't move the original
+    assert_eq!(file.read(&mut buf), Ok(4));
+    assert_eq!(buf, [4, 5, 6, 7]);
+}
+
+#[test]
+fn test_content_type() {
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.add_file_with_content_type(b"<html></html>".to_vec(), "text/html");
+    builder.add_file(vec![1, 2, 3]);
+    builder.add_file_with_content_type(b"{}".to_vec(), "application/json");
+    builder.add_file_with_content_type(vec![0; 4], "application/octet-stream");
+
+    let image_bytes = builder.finalize().expect("fs image");
+    let fs = FileSystem::mount(RamStorage::new(image_bytes)).expect("filesystem mount");
+
+    assert_eq!(
+        fs.open(0).expect("file open").content_type(),
+        Some("text/html")
+    );
+    assert_eq!(fs.open(1).expect("file open").content_type(), None);
+    assert_eq!(
+        fs.open(2).expect("file open").content_type(),
+        Some("application/json")
+    );
+    assert_eq!(read_full_file(&fs, 2), b"{}");
+    assert_eq!(
+        fs.open(3).expect("file open").content_type(),
+        Some("application/octet-stream")
+    );
+}
+
+#[test]
+fn test_content_type_too_long() {
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.add_file_with_content_type(
+        vec![],
+        "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+    );
+
+    let status = builder.finalize().expect_err("content type too long");
+    assert_eq!(status, BuilderError::ContentTypeTooLong);
+}
+
+#[test]
+fn test_out_of_space() {
+    let capacity = size_of::<FilesystemHeader>() + 2 * size_of::<DirEntry>() + 100;
+
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(capacity);
+    builder.add_file(vec![0; 60]);
+    builder.add_file(vec![0; 40]);
+    assert_eq!(builder.finalize().expect("fs image").len(), capacity);
+
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(capacity);
+    builder.add_file(vec![0; 60]);
+    builder.add_file(vec![0; 41]);
+
+    let status = builder.finalize().expect_err("out of space");
+    assert_eq!(
+        status,
+        BuilderError::OutOfSpace {
+            needed: capacity + 1,
+            capacity
+        }
+    );
+    assert_eq!(
+        status.to_string(),
+        format!(
+            "capacity exceeded: {} bytes needed, {} available",
+            capacity + 1,
+            capacity
+        )
+    );
+}
+
+#[test]
+fn test_builder_error_display() {
+    let status = BuilderError::FileTooBig {
+        size: 5_000_000_000,
+        max: u32::MAX as u64,
+    };
+    assert_eq!(
+        status.to_string(),
+        "file too big: 5000000000 bytes, max 4294967295"
+    );
+}
+
+fn eager_mount_test_image() -> Vec<u8> {
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.add_file(vec![1; 10]);
+    builder.add_file(vec![]);
+    builder.add_file(vec![2; 20]);
+
+    builder.finalize().expect("fs image").to_vec()
+}
+
+// Update header checksums after patching the directory, as if the builder wrote it
+fn update_directory_crc(image: &mut [u8]) {
+    let mut header = FilesystemHeader::from_bytes(&mut &image[..]).expect("parsing fs header");
+    let dir_end = header.dir_end().expect("directory size");
+
+    let mut dir_crc = Crc32::new();
+    dir_crc.update(&image[header.dir_start()..dir_end]);
+    header.dir_crc = dir_crc.finalize();
+    header.header_crc = header.crc();
+    header.to_bytes(&mut &mut image[..]);
+}
+
+#[test]
+fn test_mount_eager() {
+    let image = eager_mount_test_image();
+
+    let fs = FileSystem::mount_eager(
+        RamStorage::new(Bytes::from(image.clone())),
+        &mut [DirEntry::default(); 8],
+    )
+    .expect("mount");
+    assert_eq!(read_full_file(&fs, 0), vec![1; 10]);
+    assert_eq!(read_full_file(&fs, 1), vec![]);
+    assert_eq!(read_full_file(&fs, 2), vec![2; 20]);
+
+    // Each directory entry is read once
+    let storage = RecordingStorage::new(Bytes::from(image.clone()));
+    FileSystem::mount_eager(&storage, &mut [DirEntry::default(); 8]).expect("mount");
+    let entry_reads = storage
+        .reads
+        .borrow()
+        .iter()
+        .filter(|range| range.start >= dir_entry_offset(0) && range.len() == size_of::<DirEntry>())
+        .count();
+    assert_eq!(entry_reads, 3);
+
+    // Scratch space must hold the whole directory
+    let status = FileSystem::mount_eager(
+        RamStorage::new(Bytes::from(image)),
+        &mut [DirEntry::default(); 2],
+    )
+    .err();
+    assert_eq!(status, Some(Error::BufferTooSmall));
+}
+
+#[test]
+fn test_mount_eager_out_of_bounds() {
+    let mut image = eager_mount_test_image();
+    // Last file length goes past the end of storage
+    (&mut image[dir_entry_offset(2) + 4..]).put_u32(21);
+    update_directory_crc(&mut image);
+
+    let fs = FileSystem::mount(RamStorage::new(Bytes::from(image.clone()))).expect("mount");
+    assert_eq!(read_full_file(&fs, 0), vec![1; 10]);
+
+    let status = FileSystem::mount_eager(
+        RamStorage::new(Bytes::from(image)),
+        &mut [DirEntry::default(); 8],
+    )
+    .err();
+    assert_eq!(status, Some(Error::CorruptedFileSystem));
+}
+
+#[test]
+fn test_mount_eager_overlap() {
+    let mut image = eager_mount_test_image();
+    // Last file starts in the middle of the first one
+    let first_file_offset = dir_entry_offset(3) as u32;
+    (&mut image[dir_entry_offset(2)..]).put_u32(first_file_offset + 5);
+    update_directory_crc(&mut image);
+
+    let fs = FileSystem::mount(RamStorage::new(Bytes::from(image.clone()))).expect("mount");
+    assert_eq!(read_full_file(&fs, 0), vec![1; 10]);
+
+    let status = FileSystem::mount_eager(
+        RamStorage::new(Bytes::from(image)),
+        &mut [DirEntry::default(); 8],
+    )
+    .err();
+    assert_eq!(status, Some(Error::CorruptedFileSystem));
+}
+
+#[test]
+fn test_mount_eager_points_to_directory() {
+    let mut image = eager_mount_test_image();
+    (&mut image[dir_entry_offset(2)..]).put_u32(0);
+    update_directory_crc(&mut image);
+
+    let status = FileSystem::mount_eager(
+        RamStorage::new(Bytes::from(image)),
+        &mut [DirEntry::default(); 8],
+    )
+    .err();
+    assert_eq!(status, Some(Error::CorruptedFileSystem));
+}
+
+#[test]
+fn test_indices_sorted_by_size() {
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    for size in [30, 10, 0, 20, 10] {
+        builder.add_file(vec![0; size]);
+    }
+
+    let image_bytes = builder.finalize().expect("fs image");
+    let fs = FileSystem::mount(RamStorage::new(image_bytes)).expect("filesystem mount");
+
+    let mut buf = [(0, 0); 8];
+    assert_eq!(
+        fs.indices_sorted_by_size(&mut buf),
+        Ok(&[(2, 0), (1, 10), (4, 10), (3, 20), (0, 30)][..])
+    );
+
+    let mut buf = [(0, 0); 4];
+    assert_eq!(
+        fs.indices_sorted_by_size(&mut buf),
+        Err(Error::BufferTooSmall)
+    );
+}
+
+#[test]
+fn test_read_chunks_rev() {
+    let filedata: Vec<u8> = (0..100).collect();
+
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.add_file(filedata.clone());
+
+    let image_bytes = builder.finalize().expect("fs image");
+    let fs = FileSystem::mount(RamStorage::new(image_bytes)).expect("filesystem mount");
+    let mut file = fs.open(0).expect("file open");
+
+    let mut chunks = Vec::new();
+    let mut buf = [0; 16];
+    loop {
+        let len = file.read_chunks_rev(7, &mut buf).expect("read chunk");
+        if len == 0 {
+            break;
+        }
+        chunks.push(buf[..len].to_vec());
+    }
+
+    assert_eq!(chunks.len(), 15);
+    assert_eq!(chunks[0], vec![93, 94, 95, 96, 97, 98, 99]);
+    assert_eq!(chunks[14], vec![0, 1]);
+    assert_eq!(
+        chunks.into_iter().rev().flatten().collect::<Vec<_>>(),
+        filedata
+    );
+
+    // Forward cursor is not affected
+    assert_eq!(file.read(&mut buf), Ok(16));
+    assert_eq!(buf[..], filedata[..16]);
+}
+
+#[test]
+fn test_build_time() {
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.set_build_time(1_700_000_000);
+    builder.add_file(vec![1, 2, 3]);
+
+    let image_bytes = builder.finalize().expect("fs image");
+    let fs = FileSystem::mount(RamStorage::new(image_bytes)).expect("filesystem mount");
+    assert_eq!(fs.build_time(), 1_700_000_000);
+
+    let builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    let image_bytes = builder.finalize().expect("fs image");
+    let fs = FileSystem::mount(RamStorage::new(image_bytes)).expect("filesystem mount");
+    assert_eq!(fs.build_time(), 0);
+}
+
+#[test]
+fn test_file_bytes() {
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.add_file(vec![1, 2, 3]);
+    builder.add_file((0..100).collect());
+
+    let image_bytes = builder.finalize().expect("fs image");
+    let fs = FileSystem::mount(BytesStorage::new(image_bytes.clone())).expect("filesystem mount");
+
+    let file_bytes = fs.file_bytes(1).expect("file bytes");
+    assert_eq!(file_bytes, (0..100).collect::<Vec<u8>>());
+
+    // Returned bytes point into the image buffer
+    let offset = size_of::<FilesystemHeader>() + 2 * size_of::<DirEntry>() + 3;
+    assert_eq!(file_bytes.as_ptr(), image_bytes[offset..].as_ptr());
+
+    assert_eq!(fs.file_bytes(2), Err(Error::InvalidFileIndex));
+}
+
+#[test]
+fn test_max_file_size() {
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.add_file(vec![1; 10]);
+    builder.add_file(vec![2; 1000]);
+
+    let image_bytes = builder.finalize().expect("fs image");
+
+    let mut fs = FileSystem::mount(RamStorage::new(image_bytes)).expect("filesystem mount");
+    assert_eq!(read_full_file(&fs, 1), vec![2; 1000]);
+
+    fs.set_max_file_size(100);
+    assert_eq!(read_full_file(&fs, 0), vec![1; 10]);
+    let status = fs.open(1).expect_err("open oversized file");
+    assert_eq!(status, Error::FileTooLarge);
+
+    fs.set_max_file_size(1000);
+    assert_eq!(read_full_file(&fs, 1), vec![2; 1000]);
+}
+
+#[test]
+fn test_diff() {
+    let mut builder_a: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder_a.add_file(vec![1, 2, 3]);
+    builder_a.add_file(vec![4, 5, 6]);
+    builder_a.add_file(vec![7; 1000]);
+
+    let mut builder_b: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder_b.add_file(vec![1, 2, 3]);
+    builder_b.add_file(vec![4, 5, 0]);
+    builder_b.add_file(vec![7; 1000]);
+
+    let fs_a = FileSystem::mount(RamStorage::new(builder_a.finalize().expect("fs image a")))
+        .expect("filesystem mount a");
+    let fs_b = FileSystem::mount(RamStorage::new(builder_b.finalize().expect("fs image b")))
+        .expect("filesystem mount b");
+
+    assert_eq!(diff(&fs_a, &fs_a).expect("diff"), vec![]);
+    assert_eq!(
+        diff(&fs_a, &fs_b).expect("diff"),
+        vec![DiffEntry::Modified(1)]
+    );
+}
+
+#[test]
+fn test_diff_added_removed() {
+    let mut builder_a: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder_a.add_file(vec![1, 2, 3]);
+
+    let mut builder_b: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder_b.add_file(vec![1, 2]);
+    builder_b.add_file(vec![4, 5, 6]);
+
+    let fs_a = FileSystem::mount(RamStorage::new(builder_a.finalize().expect("fs image a")))
+        .expect("filesystem mount a");
+    let fs_b = FileSystem::mount(RamStorage::new(builder_b.finalize().expect("fs image b")))
+        .expect("filesystem mount b");
+
+    assert_eq!(
+        diff(&fs_a, &fs_b).expect("diff"),
+        vec![DiffEntry::Modified(0), DiffEntry::Added(1)]
+    );
+    assert_eq!(
+        diff(&fs_b, &fs_a).expect("diff"),
+        vec![DiffEntry::Modified(0), DiffEntry::Removed(1)]
+    );
+}
+
+// Single file image with contents [1, 2, 3]
+const EMBEDDED_IMAGE: &[u8] = &[
+    0x53, 0x69, 0x6d, 0x70, 0x6c, 0x65, 0x46, 0x53, // signature
+    0x00, 0x01, // version
+    0x00, 0x01, // num_files
+    0x00, 0x08, // dir_entry_size
+    0x9b, 0xfb, 0xd9, 0xd7, // image_crc
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // build_time
+    0x7e, 0xab, 0x09, 0xeb, // header_crc
+    0x00, 0x00, 0x00, 0x00, // flags
+    0x00, 0x00, 0x00, 0x32, // dir_offset
+    0x17, 0xba, 0x2e, 0xf4, // dir_crc
+    0x00, 0x00, 0x00, 0x03, // largest_file
+    0x00, 0x00, 0x00, 0x3d, // image_size
+    0x00, 0x00, 0x00, 0x3a, 0x00, 0x00, 0x00, 0x03, // DirEntry { offset: 58, length: 3 }
+    0x01, 0x02, 0x03, // file data
+];
+
+const EMBEDDED_HEADER: FilesystemHeader = match FilesystemHeader::from_slice(EMBEDDED_IMAGE) {
+    Some(header) => header,
+    None => panic!("embedded image too short"),
+};
+
+const _: () = assert!(EMBEDDED_HEADER.has_valid_signature());
+const _: () = assert!(EMBEDDED_HEADER.num_files == 1);
+
+#[test]
+fn test_embedded_image() {
+    let signature = EMBEDDED_HEADER.signature;
+    let num_files = EMBEDDED_HEADER.num_files;
+    assert_eq!(signature, simplefs::SIGNATURE);
+    assert!(FilesystemHeader::from_slice(&EMBEDDED_IMAGE[..4]).is_none());
+
+    let fs = FileSystem::mount(RamStorage::new(Bytes::from_static(EMBEDDED_IMAGE)))
+        .expect("filesystem mount");
+    assert_eq!(fs.get_num_files(), num_files);
+    assert_eq!(read_full_file(&fs, 0), vec![1, 2, 3]);
+    // Base entries don't have optional fields
+    assert_eq!(fs.open(0).expect("file open").content_type(), None);
+
+    let image_crc = EMBEDDED_HEADER.image_crc;
+    let mut buf = [0; 16];
+    assert_eq!(fs.compute_image_crc(&mut buf), Ok(image_crc));
+}
+
+#[test]
+fn test_crc32() {
+    let mut crc = Crc32::new();
+    crc.update(b"1234");
+    crc.update(b"56789");
+    assert_eq!(crc.finalize(), 0xcbf43926);
+    assert_eq!(Crc32::new().finalize(), 0);
+}
+
+#[test]
+fn test_image_crc() {
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.add_file(vec![1, 2, 3]);
+    builder.add_file((0..=255).collect());
+
+    let image_bytes = builder.finalize().expect("fs image");
+    let header = FilesystemHeader::from_bytes(&mut image_bytes.clone()).expect("parsing fs header");
+    let image_crc = header.image_crc;
+    assert_eq!(image_crc, simplefs::image_crc(&image_bytes));
+
+    let mut image = image_bytes.to_vec();
+    let fs = FileSystem::mount(RamStorage::new(image_bytes)).expect("filesystem mount");
+
+    // Scratch buffer size doesn't affect the result
+    assert_eq!(fs.compute_image_crc(&mut [0; 1]), Ok(image_crc));
+    assert_eq!(fs.compute_image_crc(&mut [0; 7]), Ok(image_crc));
+    assert_eq!(fs.compute_image_crc(&mut [0; 4096]), Ok(image_crc));
+    assert_eq!(fs.compute_image_crc(&mut []), Err(Error::BufferTooSmall));
+
+    // Storage past the end of the image is not included
+    let mut padded = image.clone();
+    padded.resize(image.len() + 100, 0xff);
+    let fs = FileSystem::mount(RamStorage::new(Bytes::from(padded))).expect("filesystem mount");
+    assert_eq!(fs.compute_image_crc(&mut [0; 64]), Ok(image_crc));
+
+    // Any change in the file data is detected
+    let last = image.len() - 1;
+    image[last] ^= 1;
+    let fs = FileSystem::mount(RamStorage::new(Bytes::from(image))).expect("filesystem mount");
+    assert_ne!(fs.compute_image_crc(&mut [0; 64]), Ok(image_crc));
+}
+
 #[derive(Debug, Clone)]
 struct QuickCheckFileData {
     data: Vec<u8>,
@@ -135,3 +829,1976 @@ fn test_valid_fs_build(files: Vec<QuickCheckFileData>) -> bool {
     })
 }
 }
+
+quickcheck! {
+fn test_corrupted_fs_read(files: Vec<QuickCheckFileData>, position: usize, mask: u8) -> bool {
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+
+    for file in &files {
+        builder.add_file(file.data.clone());
+    }
+
+    let mut image = builder.finalize().expect("fs image").to_vec();
+    let position = position % image.len();
+    image[position] ^= if mask == 0 { 0xff } else { mask };
+
+    let data_start = size_of::<FilesystemHeader>() + files.len() * size_of::<DirEntry>();
+    let corrupted_image = image.clone();
+
+    let mut fs = match FileSystem::mount(RamStorage::new(Bytes::from(image))) {
+        Ok(fs) => fs,
+        // Storage must never be accessed out of bounds
+        Err(Error::Storage(_)) => return false,
+        // Only corrupted header or directory can prevent mounting
+        Err(_) => return position < data_start,
+    };
+    // Corrupted sparse file length could make the read buffer huge
+    fs.set_max_file_size(CAPACITY);
+
+    let mut file_offset = data_start;
+    (0..fs.get_num_files() as usize).all(|i| match try_read_full_file(&fs, i) {
+        Ok(buf) => {
+            if position < data_start {
+                // Corrupted metadata may point anywhere, only check it doesn't crash
+                return true;
+            }
+
+            // Directory is intact, data is read from the original location
+            let expected = &corrupted_image[file_offset..file_offset + files[i].data.len()];
+            file_offset += files[i].data.len();
+            buf == expected
+        }
+        Err(Error::Storage(_)) => false,
+        Err(_) => position < data_start,
+    })
+}
+}
+
+fn backup_test_image(backup: bool) -> Vec<u8> {
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(4096);
+    builder.set_backup(backup);
+    builder.add_file(vec![1, 2, 3]);
+    builder.add_file(vec![4; 100]);
+
+    builder.finalize().expect("fs image").to_vec()
+}
+
+#[test]
+fn test_backup_header() {
+    // Padded to capacity, so that the copy is at the end of a partition of that size
+    let image = backup_test_image(true);
+    assert_eq!(image.len(), 4096);
+    let fs =
+        FileSystem::mount(RamStorage::new(Bytes::from(image.clone()))).expect("filesystem mount");
+    assert!(!fs.needs_repair());
+    assert_eq!(fs.image_size(), Ok(4096));
+    let header = FilesystemHeader::from_bytes(&mut &image[..]).expect("parsing fs header");
+    let image_crc = header.image_crc;
+    assert_eq!(fs.compute_image_crc(&mut [0; 64]), Ok(image_crc));
+
+    // Damage the primary header and directory, the copy is found past the padding
+    let mut damaged = image.clone();
+    damaged[FilesystemHeader::HEADER_CRC_OFFSET] ^= 0xff;
+    damaged[size_of::<FilesystemHeader>()] ^= 0xff;
+    let fs = FileSystem::mount_eager(
+        RamStorage::new(Bytes::from(damaged)),
+        &mut [DirEntry::default(); 8],
+    )
+    .expect("filesystem mount");
+    assert!(fs.needs_repair());
+    assert_eq!(fs.get_num_files(), 2);
+    assert_eq!(read_full_file(&fs, 0), vec![1, 2, 3]);
+    assert_eq!(read_full_file(&fs, 1), vec![4; 100]);
+
+    // Both copies damaged
+    let mut damaged = image;
+    damaged[FilesystemHeader::HEADER_CRC_OFFSET] ^= 0xff;
+    let backup_position = damaged.len() - size_of::<FilesystemHeader>();
+    damaged[backup_position + FilesystemHeader::HEADER_CRC_OFFSET] ^= 0xff;
+    let status = FileSystem::mount(RamStorage::new(Bytes::from(damaged))).err();
+    assert_eq!(status, Some(Error::HeaderChecksumMismatch));
+}
+
+#[test]
+fn test_no_backup_header() {
+    let mut image = backup_test_image(false);
+    image[FilesystemHeader::HEADER_CRC_OFFSET] ^= 0xff;
+    let status = FileSystem::mount(RamStorage::new(Bytes::from(image))).err();
+    assert_eq!(status, Some(Error::HeaderChecksumMismatch));
+
+    // Valid header at the end of storage, but not marked as a backup copy
+    let mut image = backup_test_image(true);
+    let backup_position = image.len() - size_of::<FilesystemHeader>();
+    let mut header =
+        FilesystemHeader::from_bytes(&mut &image[backup_position..]).expect("parsing fs header");
+    header.flags = 0;
+    header.header_crc = header.crc();
+    header.to_bytes(&mut &mut image[backup_position..]);
+    assert!(FileSystem::mount(RamStorage::new(Bytes::from(image.clone()))).is_ok());
+
+    image[FilesystemHeader::HEADER_CRC_OFFSET] ^= 0xff;
+    let status = FileSystem::mount(RamStorage::new(Bytes::from(image))).err();
+    assert_eq!(status, Some(Error::HeaderChecksumMismatch));
+}
+
+#[test]
+fn test_read_record() {
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.add_file((0..10).collect());
+    builder.add_file((0..8).collect());
+
+    let image_bytes = builder.finalize().expect("fs image");
+    let fs = FileSystem::mount(RamStorage::new(image_bytes)).expect("filesystem mount");
+
+    let mut file = fs.open(0).expect("file open");
+    assert_eq!(file.read_record::<4>(), Ok(Some([0, 1, 2, 3])));
+    assert_eq!(file.read_record::<4>(), Ok(Some([4, 5, 6, 7])));
+    assert_eq!(file.read_record::<4>(), Err(Error::UnexpectedEof));
+    // Partial record is still there for a regular read
+    let mut buf = [0; 4];
+    assert_eq!(file.read(&mut buf), Ok(2));
+    assert_eq!(buf[..2], [8, 9]);
+
+    let mut file = fs.open(1).expect("file open");
+    assert_eq!(file.read_record::<4>(), Ok(Some([0, 1, 2, 3])));
+    assert_eq!(file.read_record::<4>(), Ok(Some([4, 5, 6, 7])));
+    assert_eq!(file.read_record::<4>(), Ok(None));
+}
+
+#[test]
+fn test_add_file_at_front() {
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.add_file(vec![1]);
+    builder.add_file(vec![2, 2]);
+    builder.add_file_at_front(vec![0; 10]);
+    builder.add_file(vec![3, 3, 3]);
+
+    let image_bytes = builder.finalize().expect("fs image");
+    let fs = FileSystem::mount_eager(RamStorage::new(image_bytes), &mut [DirEntry::default(); 8])
+        .expect("filesystem mount");
+    assert_eq!(fs.get_num_files(), 4);
+    assert_eq!(read_full_file(&fs, 0), vec![0; 10]);
+    assert_eq!(read_full_file(&fs, 1), vec![1]);
+    assert_eq!(read_full_file(&fs, 2), vec![2, 2]);
+    assert_eq!(read_full_file(&fs, 3), vec![3, 3, 3]);
+}
+
+#[test]
+fn test_read_with_interrupt() {
+    let filedata: Vec<u8> = (0..3 * INTERRUPT_CHUNK_SIZE).map(|i| i as u8).collect();
+
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.add_file(filedata.clone());
+
+    let image_bytes = builder.finalize().expect("fs image");
+    let fs = FileSystem::mount(RamStorage::new(image_bytes)).expect("filesystem mount");
+
+    let mut file = fs.open(0).expect("file open");
+    let mut buf = vec![0; filedata.len()];
+    assert_eq!(
+        file.read_with_interrupt(&mut buf, || true),
+        Err(Error::Interrupted {
+            bytes_read: INTERRUPT_CHUNK_SIZE
+        })
+    );
+    assert_eq!(
+        buf[..INTERRUPT_CHUNK_SIZE],
+        filedata[..INTERRUPT_CHUNK_SIZE]
+    );
+
+    // Resume from where the interrupted read stopped
+    let mut polls = 0;
+    let rest = &mut buf[INTERRUPT_CHUNK_SIZE..];
+    assert_eq!(
+        file.read_with_interrupt(rest, || {
+            polls += 1;
+            false
+        }),
+        Ok(2 * INTERRUPT_CHUNK_SIZE)
+    );
+    assert_eq!(polls, 1);
+    assert_eq!(buf, filedata);
+}
+
+#[test]
+fn test_read_into_ring() {
+    let filedata: Vec<u8> = (1..=10).collect();
+
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.add_file(filedata);
+
+    let image_bytes = builder.finalize().expect("fs image");
+    let fs = FileSystem::mount(RamStorage::new(image_bytes)).expect("filesystem mount");
+    let mut file = fs.open(0).expect("file open");
+
+    let mut ring = [0; 8];
+    let mut head = 5;
+    assert_eq!(file.read_into_ring(&mut ring, &mut head, 6), Ok(6));
+    assert_eq!(head, 3);
+    assert_eq!(ring, [4, 5, 6, 0, 0, 1, 2, 3]);
+
+    // Length is limited by the ring size and the remaining file data
+    assert_eq!(file.read_into_ring(&mut ring, &mut head, 20), Ok(4));
+    assert_eq!(head, 7);
+    assert_eq!(ring, [4, 5, 6, 7, 8, 9, 10, 3]);
+    assert_eq!(file.read_into_ring(&mut ring, &mut head, 20), Ok(0));
+
+    assert_eq!(
+        file.read_into_ring(&mut [], &mut head, 1),
+        Err(Error::BufferTooSmall)
+    );
+}
+
+#[test]
+fn test_file_from_parts() {
+    let storage = RamStorage::new(Bytes::from((0..32).collect::<Vec<u8>>()));
+
+    let mut file = File::from_parts(&storage, 10, 4).expect("file from parts");
+    assert_eq!(file.total_size(), 4);
+    assert_eq!(file.content_type(), None);
+    let mut buf = [0; 8];
+    assert_eq!(file.read(&mut buf), Ok(4));
+    assert_eq!(buf[..4], [10, 11, 12, 13]);
+    assert_eq!(file.read(&mut buf), Ok(0));
+
+    assert!(File::from_parts(&storage, 0, 32).is_ok());
+    assert_eq!(
+        File::from_parts(&storage, 30, 4).err(),
+        Some(Error::CorruptedFileSystem)
+    );
+    assert_eq!(
+        File::from_parts(&storage, usize::MAX, 2).err(),
+        Some(Error::CorruptedFileSystem)
+    );
+}
+
+#[cfg(feature = "fuzzing")]
+#[test]
+fn test_fuzz_seed_corpus() {
+    let seed = include_bytes!("../../fuzz/corpus/mount_and_read_all/single_file");
+    assert_eq!(seed, EMBEDDED_IMAGE);
+
+    // Every truncation of a valid image must be handled without panicking
+    for len in 0..=seed.len() {
+        mount_and_read_all(&seed[..len]);
+    }
+}
+
+#[test]
+fn test_next_read_range() {
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.add_file(vec![1; 10]);
+    builder.add_file(vec![2; 20]);
+
+    let image_bytes = builder.finalize().expect("fs image");
+    let fs = FileSystem::mount(RecordingStorage::new(image_bytes)).expect("filesystem mount");
+    let mut file = fs.open(1).expect("file open");
+    let mut buf = [0; 16];
+
+    for expected_len in [16, 4, 0] {
+        let range = file.next_read_range(buf.len());
+        assert_eq!(range.len(), expected_len);
+
+        fs.storage().reads.borrow_mut().clear();
+        assert_eq!(file.read(&mut buf), Ok(expected_len));
+        let reads = fs.storage().reads.borrow().clone();
+        if expected_len == 0 {
+            assert!(reads.is_empty());
+        } else {
+            assert_eq!(reads, vec![range]);
+        }
+    }
+}
+
+#[test]
+fn test_read_limit() {
+    let filedata: Vec<u8> = (0..100).collect();
+
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.add_file(filedata.clone());
+
+    let image_bytes = builder.finalize().expect("fs image");
+    let fs = FileSystem::mount(RamStorage::new(image_bytes)).expect("filesystem mount");
+
+    let mut file = fs.open(0).expect("file open").with_read_limit(20);
+    assert_eq!(file.total_size(), 100);
+    let mut buf = [0; 16];
+    assert_eq!(file.read(&mut buf), Ok(16));
+    assert_eq!(file.read(&mut buf), Ok(4));
+    assert_eq!(buf[..4], filedata[16..20]);
+    assert_eq!(file.read(&mut buf), Ok(0));
+    // Limit applies to reads in any direction
+    assert_eq!(file.read_chunks_rev(8, &mut buf), Ok(0));
+
+    let mut file = fs.open(0).expect("file open").with_read_limit(10);
+    assert_eq!(file.read_chunks_rev(8, &mut buf), Ok(8));
+    assert_eq!(file.read(&mut buf), Ok(2));
+    assert_eq!(file.read(&mut buf), Ok(0));
+}
+
+#[test]
+fn test_top_files_by_size() {
+    let sizes = [5, 0, 12, 7, 12, 3];
+
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    for size in sizes {
+        builder.add_file(vec![0; size]);
+    }
+
+    let image_bytes = builder.finalize().expect("fs image");
+    let fs = FileSystem::mount(RamStorage::new(image_bytes)).expect("filesystem mount");
+
+    let mut out = [(0, 0); 4];
+    assert_eq!(fs.top_files_by_size(3, &mut out), Ok(3));
+    assert_eq!(out[..3], [(2, 12), (4, 12), (3, 7)]);
+
+    // Limited by the output buffer and by the number of files
+    assert_eq!(fs.top_files_by_size(10, &mut out[..2]), Ok(2));
+    assert_eq!(out[..2], [(2, 12), (4, 12)]);
+    let mut out = [(0, 0); 10];
+    assert_eq!(fs.top_files_by_size(10, &mut out), Ok(6));
+    assert_eq!(out[5], (1, 0));
+}
+
+#[test]
+fn test_header_flags() {
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.add_file(vec![1, 2, 3]);
+    let image_bytes = builder.finalize().expect("fs image");
+    let fs = FileSystem::mount(RamStorage::new(image_bytes)).expect("filesystem mount");
+    assert_eq!(fs.flags(), HeaderFlags::default());
+    assert!(!fs.flags().has_backup_header());
+
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.set_backup(true);
+    builder.add_file(vec![1, 2, 3]);
+    let image_bytes = builder.finalize().expect("fs image");
+    let fs = FileSystem::mount(RamStorage::new(image_bytes)).expect("filesystem mount");
+    assert!(fs.flags().has_backup_header());
+    assert!(!fs.flags().is_compressed());
+    assert!(!fs.flags().is_encrypted());
+
+    // Images using features this reader doesn't implement are rejected
+    for flag in [HeaderFlags::COMPRESSED, HeaderFlags::ENCRYPTED] {
+        let mut writer = BytesMut::new();
+        let mut header = FilesystemHeader {
+            signature: simplefs::SIGNATURE,
+            version: FORMAT_VERSION,
+            num_files: 0,
+            dir_entry_size: size_of::<DirEntry>() as u16,
+            image_crc: 0,
+            build_time: 0,
+            header_crc: 0,
+            flags: HeaderFlags::default().with(flag).bits(),
+            dir_offset: 0,
+            dir_crc: 0,
+            largest_file: 0,
+            image_size: 0,
+        };
+        header.header_crc = header.crc();
+        header.to_bytes(&mut writer);
+
+        let status = FileSystem::mount(RamStorage::new(writer.freeze())).err();
+        assert_eq!(status, Some(Error::UnsupportedFeature));
+    }
+}
+
+#[test]
+fn test_format_version() {
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.add_file(vec![1, 2, 3]);
+    let mut image = builder.finalize().expect("fs image").to_vec();
+    let header = FilesystemHeader::from_bytes(&mut &image[..]).expect("parsing fs header");
+    let version = header.version;
+    assert_eq!(version, FORMAT_VERSION);
+
+    // Reported even though the checksum doesn't match, the layout may not be known
+    (&mut image[8..]).put_u16(FORMAT_VERSION + 1);
+    let status = FileSystem::mount(RamStorage::new(Bytes::from(image.clone()))).err();
+    assert_eq!(status, Some(Error::UnsupportedVersion));
+    assert_eq!(
+        FileSystem::validate_prefix(&image[..]),
+        Err(Error::UnsupportedVersion)
+    );
+}
+
+#[test]
+fn test_core_builder() {
+    let mut buf = [0; 256];
+    let mut builder = CoreBuilder::new(&mut buf, 3).expect("builder");
+    builder.set_build_time(1234);
+    builder.add_file(&[1, 2, 3]).expect("add file");
+    builder.add_file(&[]).expect("add file");
+    builder.add_file(&[4; 10]).expect("add file");
+    assert_eq!(builder.add_file(&[5]), Err(Error::InvalidFileIndex));
+    let image_size = builder.finalize();
+    assert_eq!(
+        image_size,
+        size_of::<FilesystemHeader>() + 3 * size_of::<DirEntry>() + 13
+    );
+
+    // Same image as the std builder makes
+    let mut std_builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    std_builder.set_build_time(1234);
+    std_builder.add_file(vec![1, 2, 3]);
+    std_builder.add_file(vec![]);
+    std_builder.add_file(vec![4; 10]);
+    assert_eq!(
+        buf[..image_size],
+        std_builder.finalize().expect("fs image")[..]
+    );
+
+    let fs = FileSystem::mount_eager(&buf[..image_size], &mut [DirEntry::default(); 8])
+        .expect("filesystem mount");
+    assert_eq!(fs.get_num_files(), 3);
+    assert_eq!(fs.build_time(), 1234);
+    let mut data = [0; 16];
+    assert_eq!(fs.open(2).expect("file open").read(&mut data), Ok(10));
+    assert_eq!(data[..10], [4; 10]);
+
+    let mut small = [0; size_of::<FilesystemHeader>() + 2 * size_of::<DirEntry>()];
+    assert_eq!(
+        CoreBuilder::new(&mut small, 3).err(),
+        Some(Error::BufferTooSmall)
+    );
+    let mut builder = CoreBuilder::new(&mut small, 1).expect("builder");
+    assert_eq!(
+        builder.add_file(&[0; size_of::<DirEntry>() + 1]),
+        Err(Error::BufferTooSmall)
+    );
+}
+
+#[test]
+fn test_sparse_file() {
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.add_sparse_file(vec![1, 2, 3], 10);
+    builder.add_file(vec![4; 5]);
+
+    let image_bytes = builder.finalize().expect("fs image");
+    // Zero tail is not stored
+    assert_eq!(
+        image_bytes.len(),
+        size_of::<FilesystemHeader>() + 2 * size_of::<DirEntry>() + 8
+    );
+
+    let fs = FileSystem::mount_eager(
+        RecordingStorage::new(image_bytes),
+        &mut [DirEntry::default(); 8],
+    )
+    .expect("filesystem mount");
+    let mut file = fs.open(0).expect("file open");
+    assert_eq!(file.total_size(), 10);
+
+    let mut buf = [0xff; 16];
+    fs.storage().reads.borrow_mut().clear();
+    assert_eq!(file.read(&mut buf[..2]), Ok(2));
+    assert_eq!(file.read(&mut buf[2..]), Ok(8));
+    assert_eq!(buf[..10], [1, 2, 3, 0, 0, 0, 0, 0, 0, 0]);
+    // Only the stored part is read from storage
+    let reads = fs.storage().reads.borrow().clone();
+    assert_eq!(reads.iter().map(|range| range.len()).sum::<usize>(), 3);
+
+    let mut file = fs.open(0).expect("file open");
+    assert_eq!(file.read_chunks_rev(4, &mut buf), Ok(4));
+    assert_eq!(buf[..4], [0; 4]);
+
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.add_sparse_file(vec![1, 2, 3], 2);
+    assert_eq!(
+        builder.finalize().err(),
+        Some(BuilderError::LogicalLengthTooShort)
+    );
+}
+
+#[test]
+fn test_dir_entry_offset() {
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    for size in 1..=5 {
+        builder.add_file(vec![size as u8; size]);
+    }
+    let image = builder.finalize().expect("fs image");
+
+    for index in 0..5 {
+        assert_eq!(
+            dir_entry_offset(index),
+            size_of::<FilesystemHeader>() + index * size_of::<DirEntry>()
+        );
+
+        let direntry =
+            DirEntry::from_bytes(&mut &image[dir_entry_offset(index)..]).expect("dir entry");
+        assert_eq!(direntry.length as usize, index + 1);
+    }
+}
+
+#[test]
+fn test_read_view() {
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.add_file(b"key=value".to_vec());
+
+    let image_bytes = builder.finalize().expect("fs image");
+    let fs = FileSystem::mount(RamStorage::new(image_bytes)).expect("filesystem mount");
+    let mut file = fs.open(0).expect("file open");
+
+    let mut buf = [0; 16];
+    let view = file.read_view(&mut buf, 4).expect("read view");
+    assert_eq!(view, b"key=");
+    let view = file.read_view(&mut buf, 100).expect("read view");
+    assert_eq!(&view[..3], b"val");
+    assert_eq!(view.len(), 5);
+    assert_eq!(file.read_view(&mut buf, 100), Ok(&[][..]));
+}
+
+// Image with a valid header claiming a directory of num_files entries of dir_entry_size
+// bytes at dir_offset, padded with zeros to storage_size bytes
+fn raw_directory_image(
+    num_files: u16,
+    dir_entry_size: u16,
+    dir_offset: u32,
+    storage_size: usize,
+) -> Bytes {
+    let mut header = FilesystemHeader {
+        signature: simplefs::SIGNATURE,
+        version: FORMAT_VERSION,
+        num_files,
+        dir_entry_size,
+        image_crc: 0,
+        build_time: 0,
+        header_crc: 0,
+        flags: 0,
+        dir_offset,
+        dir_crc: 0,
+        largest_file: 0,
+        image_size: u32::MAX,
+    };
+    header.header_crc = header.crc();
+
+    let mut writer = BytesMut::new();
+    header.to_bytes(&mut writer);
+    writer.resize(storage_size.max(size_of::<FilesystemHeader>()), 0);
+    writer.freeze()
+}
+
+#[test]
+fn test_huge_directory() {
+    // Largest possible directory at the largest possible offset. Its end doesn't fit
+    // in u32, so computing it overflows on targets with 32-bit usize. Elsewhere it is
+    // rejected by the capacity check.
+    let image = raw_directory_image(u16::MAX, u16::MAX, u32::MAX, 64);
+
+    let status = FileSystem::mount(RamStorage::new(image.clone())).err();
+    assert_eq!(status, Some(Error::CorruptedFileSystem));
+    let status = FileSystem::mount_lenient(RamStorage::new(image.clone())).err();
+    assert_eq!(status, Some(Error::CorruptedFileSystem));
+    assert_eq!(
+        FileSystem::validate_prefix(&image[..]),
+        Err(Error::CorruptedFileSystem)
+    );
+}
+
+quickcheck! {
+fn test_directory_past_storage(
+    num_files: u16,
+    dir_entry_size: u16,
+    dir_offset: u32,
+    storage_size: u16
+) -> bool {
+    let dir_entry_size = dir_entry_size.max(DirEntry::MIN_SIZE as u16);
+    let dir_offset = if (dir_offset as usize) < size_of::<FilesystemHeader>() {
+        0
+    } else {
+        dir_offset
+    };
+    let image = raw_directory_image(num_files, dir_entry_size, dir_offset, storage_size as usize);
+
+    // Computed in u64, where it can't overflow
+    let dir_start = match dir_offset {
+        0 => size_of::<FilesystemHeader>() as u64,
+        dir_offset => dir_offset as u64,
+    };
+    let dir_end = dir_start + num_files as u64 * dir_entry_size as u64;
+
+    let len = image.len() as u64;
+    let status = FileSystem::mount(RamStorage::new(image)).err();
+    if dir_end > len {
+        status == Some(Error::CorruptedFileSystem)
+    } else {
+        // Storage must never be accessed out of bounds
+        !matches!(status, Some(Error::Storage(_)))
+    }
+}
+}
+
+#[test]
+fn test_read_cstr() {
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.add_file(b"first\0second\0".to_vec());
+    builder.add_file(b"long string\0".to_vec());
+    builder.add_file(b"bad \xff\0".to_vec());
+    builder.add_file(b"unterminated".to_vec());
+
+    let image_bytes = builder.finalize().expect("fs image");
+    let fs = FileSystem::mount(RamStorage::new(image_bytes)).expect("filesystem mount");
+    let mut buf = [0; 8];
+
+    let mut file = fs.open(0).expect("file open");
+    assert_eq!(file.read_cstr(&mut buf), Ok("first"));
+    assert_eq!(file.read_cstr(&mut buf), Ok("second"));
+    assert_eq!(file.read_cstr(&mut buf), Err(Error::UnexpectedEof));
+
+    let mut file = fs.open(1).expect("file open");
+    assert_eq!(file.read_cstr(&mut buf), Err(Error::BufferTooSmall));
+    // Cursor is not moved by a failed read
+    assert_eq!(file.read_cstr(&mut [0; 16]), Ok("long string"));
+
+    let mut file = fs.open(2).expect("file open");
+    assert_eq!(file.read_cstr(&mut buf), Err(Error::InvalidUtf8));
+
+    let mut file = fs.open(3).expect("file open");
+    assert_eq!(file.read_cstr(&mut [0; 16]), Err(Error::UnexpectedEof));
+
+    // Only consumed bytes count against the read limit, not the read-ahead
+    let mut file = fs.open(0).expect("file open").with_read_limit(13);
+    assert_eq!(file.read_cstr(&mut [0; 16]), Ok("first"));
+    assert_eq!(file.read_cstr(&mut [0; 16]), Ok("second"));
+    assert_eq!(file.read(&mut buf), Ok(0));
+
+    let mut file = fs.open(1).expect("file open").with_read_limit(12);
+    assert_eq!(file.read_cstr(&mut buf), Err(Error::BufferTooSmall));
+    assert_eq!(file.read_cstr(&mut [0; 16]), Ok("long string"));
+}
+
+#[test]
+fn test_manifest() {
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.add_file(b"123456789".to_vec());
+    builder.add_file(vec![]);
+
+    let image_bytes = builder.finalize().expect("fs image");
+    let fs = FileSystem::mount(RamStorage::new(image_bytes)).expect("filesystem mount");
+    let names = ["check.txt".to_string(), "dir/\"quoted\"".to_string()];
+
+    let data_start = dir_entry_offset(2);
+    let expected = format!(
+        r#"[
+  {{"index": 0, "name": "check.txt", "offset": {}, "length": 9, "crc": {}}},
+  {{"index": 1, "name": "dir/\"quoted\"", "offset": {}, "length": 0, "crc": 0}}
+]
+"#,
+        data_start,
+        0xcbf43926u32,
+        data_start + 9
+    );
+    assert_eq!(manifest(&fs, &names).expect("manifest"), expected);
+}
+
+#[test]
+fn test_verify_manifest() {
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.add_file(b"123456789".to_vec());
+    builder.add_file(vec![2; 20]);
+    let image = builder.finalize().expect("fs image");
+    let fs = FileSystem::mount(RamStorage::new(image.clone())).expect("filesystem mount");
+
+    let names = ["check.txt".to_string(), "tab\t\"\u{1}".to_string()];
+    let entries = parse_manifest(&manifest(&fs, &names).expect("manifest")).expect("parse");
+    assert_eq!(
+        entries,
+        vec![
+            ManifestEntry {
+                index: 0,
+                name: names[0].clone(),
+                length: 9,
+                crc: 0xcbf43926,
+            },
+            ManifestEntry {
+                index: 1,
+                name: names[1].clone(),
+                length: 20,
+                crc: {
+                    let mut crc = Crc32::new();
+                    crc.update(&[2; 20]);
+                    crc.finalize()
+                },
+            },
+        ]
+    );
+    assert_eq!(verify(&fs, &entries), Ok(vec![]));
+
+    // Tampered file data
+    let mut tampered = image.to_vec();
+    tampered[dir_entry_offset(2) + 20] ^= 0xff;
+    let tampered_fs =
+        FileSystem::mount(RamStorage::new(Bytes::from(tampered))).expect("filesystem mount");
+    assert_eq!(
+        verify(&tampered_fs, &entries),
+        Ok(vec![Mismatch::Crc {
+            index: 1,
+            name: names[1].clone()
+        }])
+    );
+
+    let mut other_entries = entries.clone();
+    other_entries[0].length = 10;
+    other_entries[1].index = 2;
+    assert_eq!(
+        verify(&fs, &other_entries),
+        Ok(vec![
+            Mismatch::Length {
+                index: 0,
+                name: names[0].clone()
+            },
+            Mismatch::Missing {
+                index: 2,
+                name: names[1].clone()
+            },
+            Mismatch::Unlisted(1),
+        ])
+    );
+
+    assert_eq!(parse_manifest(" [ ]\n"), Ok(vec![]));
+    assert_eq!(
+        parse_manifest(r#"[{"index": 0, "name": "a"}]"#),
+        Err(ParseError { position: 1 })
+    );
+    assert_eq!(
+        parse_manifest(r#"[{"index": -1}]"#),
+        Err(ParseError { position: 11 })
+    );
+    assert_eq!(parse_manifest("[] x"), Err(ParseError { position: 3 }));
+}
+
+#[test]
+fn test_validate_prefix() {
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.add_file(vec![1; 10]);
+    builder.add_file(vec![2; 20]);
+    let image = builder.finalize().expect("fs image");
+    let data_start = dir_entry_offset(2);
+
+    assert_eq!(
+        FileSystem::validate_prefix(&image[..10]),
+        Ok(PrefixInfo::Incomplete {
+            needed: size_of::<FilesystemHeader>()
+        })
+    );
+    // Truncated in the middle of the directory
+    assert_eq!(
+        FileSystem::validate_prefix(&image[..data_start - 1]),
+        Ok(PrefixInfo::Incomplete { needed: data_start })
+    );
+
+    let complete = PrefixInfo::Complete {
+        num_files: 2,
+        image_size: image.len(),
+    };
+    assert_eq!(
+        FileSystem::validate_prefix(&image[..data_start]),
+        Ok(complete)
+    );
+    assert_eq!(FileSystem::validate_prefix(&image), Ok(complete));
+
+    let mut corrupted = image.to_vec();
+    corrupted[FilesystemHeader::HEADER_CRC_OFFSET] ^= 0xff;
+    assert_eq!(
+        FileSystem::validate_prefix(&corrupted[..data_start]),
+        Err(Error::HeaderChecksumMismatch)
+    );
+
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.set_backup(true);
+    builder.add_file(vec![1; 10]);
+    let image = builder.finalize().expect("fs image");
+    assert_eq!(
+        FileSystem::validate_prefix(&image[..dir_entry_offset(1)]),
+        Ok(PrefixInfo::Complete {
+            num_files: 1,
+            image_size: image.len(),
+        })
+    );
+}
+
+#[test]
+fn test_file_equals() {
+    let filedata: Vec<u8> = (0..200).collect();
+
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.add_file(filedata.clone());
+
+    let image_bytes = builder.finalize().expect("fs image");
+    let fs = FileSystem::mount(RamStorage::new(image_bytes)).expect("filesystem mount");
+
+    assert_eq!(fs.open(0).expect("file open").equals(&filedata), Ok(true));
+    assert_eq!(
+        fs.open(0).expect("file open").equals(&filedata[..199]),
+        Ok(false)
+    );
+
+    let mut different = filedata.clone();
+    different[150] ^= 1;
+    assert_eq!(fs.open(0).expect("file open").equals(&different), Ok(false));
+
+    // Compares from the current read position
+    let mut file = fs.open(0).expect("file open");
+    file.read(&mut [0; 100]).expect("read");
+    assert_eq!(file.equals(&filedata[100..]), Ok(true));
+}
+
+#[test]
+fn test_strict_size() {
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.add_file(vec![1; 10]);
+    builder.add_file(vec![]);
+    let image = builder.finalize().expect("fs image").to_vec();
+
+    let strict = MountOptions::new().strict_size(true);
+    let fs = FileSystem::mount_with(image.as_slice(), strict).expect("filesystem mount");
+    assert_eq!(fs.image_size(), Ok(image.len()));
+
+    let mut padded = image.clone();
+    padded.extend_from_slice(&[0; 16]);
+    assert!(FileSystem::mount_with(padded.as_slice(), MountOptions::new()).is_ok());
+    assert_eq!(
+        FileSystem::mount_with(padded.as_slice(), strict).err(),
+        Some(Error::UnexpectedTrailingData)
+    );
+
+    // Backup copy is part of the image
+    let image = backup_test_image(true);
+    assert!(FileSystem::mount_with(image.as_slice(), strict).is_ok());
+}
+
+#[test]
+fn test_directory_alignment() {
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder
+        .set_directory_alignment(256)
+        .expect("directory alignment");
+    builder.set_backup(true);
+    builder.add_file(vec![1; 10]);
+    builder.add_file(vec![2; 20]);
+
+    let image = builder.finalize().expect("fs image");
+    let header = FilesystemHeader::from_bytes(&mut image.clone()).expect("parsing fs header");
+    assert_eq!(header.dir_start(), 256);
+
+    let direntry = DirEntry::from_bytes(&mut &image[256..]).expect("dir entry");
+    assert_eq!(direntry.offset as usize, 256 + 2 * size_of::<DirEntry>());
+    assert_eq!({ direntry.length }, 10);
+
+    let strict = MountOptions::new().strict_size(true);
+    let fs = FileSystem::mount_with(RamStorage::new(image.clone()), strict).expect("mount");
+    assert_eq!(read_full_file(&fs, 0), vec![1; 10]);
+    assert_eq!(read_full_file(&fs, 1), vec![2; 20]);
+    assert!(FileSystem::mount_eager(
+        RamStorage::new(image.clone()),
+        &mut [DirEntry::default(); 8]
+    )
+    .is_ok());
+    assert_eq!(
+        FileSystem::validate_prefix(&image[..256 + 2 * size_of::<DirEntry>()]),
+        Ok(PrefixInfo::Complete {
+            num_files: 2,
+            image_size: image.len()
+        })
+    );
+
+    // Backup directory doesn't depend on alignment
+    let mut damaged = image.to_vec();
+    damaged[FilesystemHeader::HEADER_CRC_OFFSET] ^= 0xff;
+    let fs = FileSystem::mount_eager(
+        RamStorage::new(Bytes::from(damaged)),
+        &mut [DirEntry::default(); 8],
+    )
+    .expect("mount");
+    assert!(fs.needs_repair());
+    assert_eq!(read_full_file(&fs, 1), vec![2; 20]);
+
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    for alignment in [0, 48, usize::MAX] {
+        assert_eq!(
+            builder.set_directory_alignment(alignment),
+            Err(BuilderError::InvalidDirectoryAlignment { alignment })
+        );
+    }
+}
+
+#[test]
+fn test_file_index() {
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.add_file(vec![1; 10]);
+    builder.add_file(vec![2; 20]);
+
+    let image_bytes = builder.finalize().expect("fs image");
+    let fs = FileSystem::mount(RamStorage::new(image_bytes)).expect("filesystem mount");
+
+    let indices: Vec<FileIndex> = fs.file_indices().collect();
+    assert_eq!(indices, vec![FileIndex(0), FileIndex(1)]);
+    let sizes: Vec<usize> = fs
+        .file_indices()
+        .map(|index| fs.open_index(index).expect("file open").total_size())
+        .collect();
+    assert_eq!(sizes, vec![10, 20]);
+
+    let index = FileIndex::try_from(1usize).expect("index");
+    assert_eq!(index, FileIndex::from(1u16));
+    assert_eq!(usize::from(index), 1);
+    assert!(FileIndex::try_from(u16::MAX as usize + 1).is_err());
+    assert_eq!(
+        fs.open_index(FileIndex(2)).err(),
+        Some(Error::InvalidFileIndex)
+    );
+}
+
+#[test]
+fn test_file_read_header() {
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.add_file(b"\x89PNG\r\n\x1a\n".to_vec());
+    builder.add_file(b"GIF89a".to_vec());
+    builder.add_file(b"BM".to_vec());
+
+    let image_bytes = builder.finalize().expect("fs image");
+    let fs = FileSystem::mount(RamStorage::new(image_bytes)).expect("filesystem mount");
+
+    let mut file = fs.open(0).expect("file open");
+    assert_eq!(file.read_header::<4>(), Ok(*b"\x89PNG"));
+    let mut buf = [0; 8];
+    assert_eq!(file.read(&mut buf), Ok(4));
+    assert_eq!(buf[..4], *b"\r\n\x1a\n");
+    // Always reads from the start of the file
+    assert_eq!(file.read_header::<4>(), Ok(*b"\x89PNG"));
+
+    let mut file = fs.open(1).expect("file open");
+    assert_eq!(file.read_header::<4>(), Ok(*b"GIF8"));
+
+    let mut file = fs.open(2).expect("file open");
+    assert_eq!(file.read_header::<4>(), Err(Error::UnexpectedEof));
+}
+
+#[test]
+fn test_open_with_entry() {
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.add_file(vec![1; 10]);
+    builder.add_file_with_content_type(vec![2; 20], "text/css");
+
+    let image_bytes = builder.finalize().expect("fs image");
+    let fs = FileSystem::mount(RamStorage::new(image_bytes)).expect("filesystem mount");
+
+    let (file, direntry) = fs.open_with_entry(1).expect("file open");
+    assert_eq!(direntry.offset as usize, dir_entry_offset(2) + 10);
+    assert_eq!({ direntry.length }, 20);
+    assert_eq!(direntry.content_type(), Some("text/css"));
+    assert_eq!(file.total_size(), direntry.length as usize);
+    assert_eq!(file.next_read_range(100).start, direntry.offset as usize);
+
+    assert_eq!(fs.open_with_entry(2).err(), Some(Error::InvalidFileIndex));
+}
+
+#[test]
+fn test_directory_crc() {
+    let mut image = eager_mount_test_image();
+    let header = FilesystemHeader::from_bytes(&mut &image[..]).expect("parsing fs header");
+    let mut dir_crc = Crc32::new();
+    dir_crc.update(&image[header.dir_start()..header.dir_end().expect("directory size")]);
+    let stored_crc = header.dir_crc;
+    assert_eq!(stored_crc, dir_crc.finalize());
+
+    // Content type is not used to locate file data, but is still covered
+    image[dir_entry_offset(1) + 8] ^= 1;
+    let status = FileSystem::mount(RamStorage::new(Bytes::from(image.clone()))).err();
+    assert_eq!(status, Some(Error::DirectoryChecksumMismatch));
+    assert_eq!(
+        FileSystem::validate_prefix(&image[..dir_entry_offset(3)]),
+        Err(Error::DirectoryChecksumMismatch)
+    );
+
+    // Backup directory is used if the primary one is damaged
+    let mut image = backup_test_image(true);
+    image[dir_entry_offset(0)] ^= 1;
+    let fs = FileSystem::mount(RamStorage::new(Bytes::from(image))).expect("filesystem mount");
+    assert!(fs.needs_repair());
+    assert_eq!(read_full_file(&fs, 1), vec![4; 100]);
+}
+
+#[test]
+fn test_add_file_at() {
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.add_file(vec![1; 100]);
+    builder.add_file_at(0x1000, vec![0xb0; 16]);
+    builder.add_file(vec![2; 4000]);
+    builder.add_file(vec![3; 10]);
+
+    let image = builder.finalize().expect("fs image");
+    assert_eq!(image[0x1000..0x1010], [0xb0; 16]);
+
+    let fs = FileSystem::mount_eager(
+        RamStorage::new(image.clone()),
+        &mut [DirEntry::default(); 8],
+    )
+    .expect("filesystem mount");
+    let offsets: Vec<u32> = (0..4)
+        .map(|index| fs.read_dir_entry(index).expect("dir entry").offset)
+        .collect();
+    let data_start = dir_entry_offset(4) as u32;
+    // Second auto-placed file doesn't fit before the fixed one
+    assert_eq!(offsets, vec![data_start, 0x1000, 0x1010, 0x1010 + 4000]);
+    assert_eq!(read_full_file(&fs, 1), vec![0xb0; 16]);
+    assert_eq!(read_full_file(&fs, 2), vec![2; 4000]);
+    assert_eq!(read_full_file(&fs, 3), vec![3; 10]);
+    // Gap is zero-filled
+    assert!(image[data_start as usize + 100..0x1000]
+        .iter()
+        .all(|&byte| byte == 0));
+
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.add_file_at(0x1000, vec![1; 16]);
+    builder.add_file_at(0x1008, vec![2; 16]);
+    assert_eq!(
+        builder.finalize().err(),
+        Some(BuilderError::OverlappingFile { offset: 0x1008 })
+    );
+
+    // Fixed file can't overlap the directory
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.add_file_at(0, vec![1; 16]);
+    assert_eq!(
+        builder.finalize().err(),
+        Some(BuilderError::OverlappingFile { offset: 0 })
+    );
+
+    // File end doesn't fit in usize
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.add_file_at(usize::MAX - 8, vec![1; 16]);
+    assert_eq!(
+        builder.finalize().err(),
+        Some(BuilderError::ImageTooLargeForFormat {
+            size: usize::MAX - 8
+        })
+    );
+}
+
+#[cfg(feature = "sha256")]
+#[test]
+fn test_sha256() {
+    fn hex_digest(message: &[u8], piece_size: usize) -> String {
+        let mut hasher = Sha256::new();
+        for piece in message.chunks(piece_size) {
+            hasher.update(piece);
+        }
+        hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    // FIPS 180-4 examples. Padding of the 56-byte message spills into a second block,
+    // the 112-byte message takes two blocks before padding.
+    let million = vec![b'a'; 1_000_000];
+    let vectors: [(&[u8], &str); 5] = [
+        (
+            b"",
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+        ),
+        (
+            b"abc",
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+        ),
+        (
+            b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq",
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1",
+        ),
+        (
+            b"abcdefghbcdefghicdefghijdefghijkefghijklfghijklmghijklmn\
+              hijklmnoijklmnopjklmnopqklmnopqrlmnopqrsmnopqrstnopqrstu",
+            "cf5b16a778af8380036ce59e7b0492370b249b11e8f07a51afac45037afee9d1",
+        ),
+        (
+            &million,
+            "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0",
+        ),
+    ];
+
+    for (message, digest) in vectors {
+        // At once, and in pieces that straddle block boundaries in different ways
+        for piece_size in [message.len().max(1), 1, 7, 63, 64, 65, 1000] {
+            assert_eq!(hex_digest(message, piece_size), digest);
+        }
+    }
+}
+
+#[cfg(feature = "sha256")]
+#[test]
+fn test_verify_digest() {
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.add_file(vec![1; 1000]);
+    builder.add_file(vec![2; 3000]);
+    let image = builder.finalize().expect("fs image");
+
+    let mut hasher = Sha256::new();
+    hasher.update(&image);
+    let digest = hasher.finalize();
+
+    // Padding after the image is not part of the digest
+    let mut storage = image.to_vec();
+    storage.resize(CAPACITY, 0xff);
+    let fs = FileSystem::mount(storage.as_slice()).expect("filesystem mount");
+
+    let mut buf = [0; 100];
+    assert_eq!(fs.verify_digest(&digest, &mut buf), Ok(true));
+
+    let mut wrong_digest = digest;
+    wrong_digest[0] ^= 1;
+    assert_eq!(fs.verify_digest(&wrong_digest, &mut buf), Ok(false));
+    assert_eq!(fs.verify_digest(&digest[..16], &mut buf), Ok(false));
+
+    storage[image.len() - 1] ^= 1;
+    let fs = FileSystem::mount(storage.as_slice()).expect("filesystem mount");
+    assert_eq!(fs.verify_digest(&digest, &mut buf), Ok(false));
+}
+
+#[test]
+fn test_file_at_storage_offset() {
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.add_file(vec![1; 100]);
+    builder.add_file(vec![]);
+    builder.add_file_at(0x1000, vec![2; 16]);
+    let image = builder.finalize().expect("fs image");
+    let fs = FileSystem::mount(RamStorage::new(image)).expect("filesystem mount");
+
+    let data_start = dir_entry_offset(3);
+    assert_eq!(fs.file_at_storage_offset(0), Ok(None));
+    assert_eq!(fs.file_at_storage_offset(data_start - 1), Ok(None));
+    assert_eq!(fs.file_at_storage_offset(data_start), Ok(Some(0)));
+    assert_eq!(fs.file_at_storage_offset(data_start + 99), Ok(Some(0)));
+    // Padding before the fixed file
+    assert_eq!(fs.file_at_storage_offset(data_start + 100), Ok(None));
+    assert_eq!(fs.file_at_storage_offset(0x1000), Ok(Some(2)));
+    assert_eq!(fs.file_at_storage_offset(0x100f), Ok(Some(2)));
+    assert_eq!(fs.file_at_storage_offset(0x1010), Ok(None));
+}
+
+#[test]
+fn test_for_each_chunk() {
+    let data: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.add_file(data.clone());
+    builder.add_sparse_file(vec![7; 10], 100);
+    let image = builder.finalize().expect("fs image");
+    let fs = FileSystem::mount(RamStorage::new(image)).expect("filesystem mount");
+
+    let mut file = fs.open(0).expect("file open");
+    let mut head = [0; 10];
+    file.read(&mut head).expect("file read");
+
+    let mut chunks = Vec::new();
+    let mut contents = Vec::new();
+    let mut scratch = [0; 64];
+    file.for_each_chunk(&mut scratch, |chunk| {
+        chunks.push(chunk.len());
+        contents.extend_from_slice(chunk);
+    })
+    .expect("file read");
+    assert_eq!(contents, data[10..]);
+    assert_eq!(chunks.len(), 990usize.div_ceil(64));
+
+    let mut contents = Vec::new();
+    let mut file = fs.open(1).expect("file open");
+    file.for_each_chunk(&mut scratch, |chunk| contents.extend_from_slice(chunk))
+        .expect("file read");
+    let mut expected = vec![7; 10];
+    expected.resize(100, 0);
+    assert_eq!(contents, expected);
+
+    let mut file = fs.open(0).expect("file open");
+    assert_eq!(
+        file.for_each_chunk(&mut [], |_| ()),
+        Err(Error::BufferTooSmall)
+    );
+}
+
+#[test]
+fn test_repack_capacity() {
+    const MIB: usize = 1024 * 1024;
+
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(4 * MIB);
+    builder.set_build_time(1234);
+    builder.add_file_with_content_type(vec![1; 100 * 1024], "text/html");
+    builder.add_sparse_file(vec![2; 1000], 200 * 1024);
+    builder.add_file_at(3 * MIB, vec![3; 16]);
+    let image = builder.finalize().expect("fs image");
+    let fs = FileSystem::mount(RamStorage::new(image)).expect("filesystem mount");
+
+    let repacked = SimpleFsBuilder::from_image(&fs)
+        .expect("image read")
+        .with_capacity(MIB)
+        .finalize()
+        .expect("fs image");
+    assert!(repacked.len() <= MIB);
+
+    let repacked_fs = FileSystem::mount(RamStorage::new(repacked)).expect("filesystem mount");
+    assert_eq!(repacked_fs.build_time(), 1234);
+    assert_eq!(fs.version(), FORMAT_VERSION);
+    assert_eq!(repacked_fs.version(), fs.version());
+    assert_eq!(diff(&fs, &repacked_fs), Ok(vec![]));
+    for index in 0..3 {
+        let (_, old_entry) = fs.open_with_entry(index).expect("file open");
+        let (_, new_entry) = repacked_fs.open_with_entry(index).expect("file open");
+        assert_eq!(old_entry.content_type(), new_entry.content_type());
+        assert_eq!({ old_entry.length }, { new_entry.length });
+        assert_eq!({ old_entry.logical_length }, { new_entry.logical_length });
+    }
+
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(4 * MIB);
+    builder.add_file(vec![1; 2 * MIB]);
+    let image = builder.finalize().expect("fs image");
+    let fs = FileSystem::mount(RamStorage::new(image)).expect("filesystem mount");
+
+    let result = SimpleFsBuilder::from_image(&fs)
+        .expect("image read")
+        .with_capacity(MIB)
+        .finalize();
+    assert!(matches!(
+        result,
+        Err(BuilderError::OutOfSpace { capacity: MIB, .. })
+    ));
+}
+
+#[test]
+fn test_count_where() {
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    for size in [10, 1000, 0, 5000, 100] {
+        builder.add_file(vec![1; size]);
+    }
+    builder.add_sparse_file(vec![2; 10], 2000);
+    let image = builder.finalize().expect("fs image");
+    let fs = FileSystem::mount(RamStorage::new(image)).expect("filesystem mount");
+
+    assert_eq!(fs.count_where(|direntry| direntry.length > 100), Ok(2));
+    assert_eq!(
+        fs.count_where(|direntry| direntry.logical_size() > 100),
+        Ok(3)
+    );
+    assert_eq!(fs.count_where(|_| true), Ok(6));
+    assert_eq!(fs.count_where(|_| false), Ok(0));
+}
+
+#[test]
+fn test_read_directory() {
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.add_file_with_content_type(vec![1; 10], "text/css");
+    builder.add_file(vec![2; 20]);
+    builder.add_sparse_file(vec![3; 30], 300);
+    let image = builder.finalize().expect("fs image");
+    let fs = FileSystem::mount(RamStorage::new(image)).expect("filesystem mount");
+
+    let mut entries = [DirEntry::default(); 4];
+    let directory = fs.read_directory(&mut entries).expect("directory read");
+    assert_eq!(directory.len(), 3);
+    for (index, direntry) in directory.iter().enumerate() {
+        assert_eq!(*direntry, fs.read_dir_entry(index).expect("dir entry"));
+    }
+    assert_eq!(directory[0].content_type(), Some("text/css"));
+    assert_eq!({ directory[1].length }, 20);
+    assert_eq!({ directory[1].offset }, { directory[0].offset } + 10);
+    assert_eq!(directory[2].logical_size(), 300);
+
+    let mut entries = [DirEntry::default(); 2];
+    assert_eq!(
+        fs.read_directory(&mut entries).err(),
+        Some(Error::BufferTooSmall)
+    );
+}
+
+#[test]
+fn test_require_nonempty() {
+    let builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    let image = builder.finalize().expect("fs image");
+    let fs = FileSystem::mount(RamStorage::new(image)).expect("filesystem mount");
+    assert!(fs.is_empty());
+
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.require_nonempty();
+    assert_eq!(builder.finalize().err(), Some(BuilderError::NoFiles));
+
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.require_nonempty();
+    builder.add_file(vec![]);
+    let image = builder.finalize().expect("fs image");
+    let fs = FileSystem::mount(RamStorage::new(image)).expect("filesystem mount");
+    assert_eq!(fs.get_num_files(), 1);
+}
+
+#[test]
+fn test_read_pod() {
+    let mut data = vec![0xaa];
+    data.extend_from_slice(&0x1122334455667788u64.to_le_bytes());
+    data.extend_from_slice(&[1, 2, 3]);
+
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.add_file(data);
+    let image = builder.finalize().expect("fs image");
+    let fs = FileSystem::mount(RamStorage::new(image)).expect("filesystem mount");
+
+    let mut file = fs.open(0).expect("file open");
+    assert_eq!(file.read_pod::<1>(), Ok([0xaa]));
+    assert_eq!(
+        file.read_pod::<8>().map(u64::from_le_bytes),
+        Ok(0x1122334455667788)
+    );
+    assert_eq!(file.read_pod::<4>(), Err(Error::UnexpectedEof));
+    assert_eq!(file.read_pod::<3>(), Ok([1, 2, 3]));
+    assert_eq!(file.read_pod::<0>(), Ok([]));
+}
+
+#[test]
+fn test_chain_storage() {
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    let data: Vec<u8> = (0..3000).map(|i| (i % 251) as u8).collect();
+    builder.add_file(data.clone());
+    builder.add_file(vec![5; 100]);
+    let image = builder.finalize().expect("fs image");
+
+    // Split in the middle of the first file
+    let (first, second) = image.split_at(1000);
+    let storage = ChainStorage::new(first, second);
+    assert_eq!(storage.capacity(), image.len());
+
+    let mut buf = [0; 10];
+    storage.read(995, &mut buf).expect("storage read");
+    assert_eq!(buf, image[995..1005]);
+    storage.read(1000, &mut buf).expect("storage read");
+    assert_eq!(buf, image[1000..1010]);
+    storage.read(990, &mut buf).expect("storage read");
+    assert_eq!(buf, image[990..1000]);
+
+    let fs = FileSystem::mount(storage).expect("filesystem mount");
+    assert_eq!(fs.open(0).expect("file open").equals(&data), Ok(true));
+    assert_eq!(fs.open(1).expect("file open").equals(&[5; 100]), Ok(true));
+
+    let storage = fs.into_storage();
+    assert_eq!(storage.read(image.len() - 5, &mut buf), Err(OutOfBounds));
+}
+
+#[test]
+fn test_open_expecting_magic() {
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.add_file(b"\x89PNG\r\n\x1a\nrest of image".to_vec());
+    builder.add_file(b"\x89PN".to_vec());
+    let image = builder.finalize().expect("fs image");
+    let fs = FileSystem::mount(RamStorage::new(image)).expect("filesystem mount");
+
+    let mut file = fs
+        .open_expecting_magic(0, b"\x89PNG\r\n\x1a\n")
+        .expect("file open");
+    let mut buf = [0; 4];
+    assert_eq!(file.read(&mut buf), Ok(4));
+    assert_eq!(&buf, b"\x89PNG");
+
+    assert!(fs.open_expecting_magic(0, b"").is_ok());
+    assert_eq!(
+        fs.open_expecting_magic(0, b"GIF8").err(),
+        Some(Error::MagicMismatch)
+    );
+    // File shorter than magic
+    assert_eq!(
+        fs.open_expecting_magic(1, b"\x89PNG").err(),
+        Some(Error::MagicMismatch)
+    );
+    assert_eq!(
+        fs.open_expecting_magic(2, b"\x89PNG").err(),
+        Some(Error::InvalidFileIndex)
+    );
+}
+
+#[test]
+fn test_files_rev() {
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    for index in 0..5u8 {
+        builder.add_file(vec![index; index as usize * 10]);
+    }
+    let image = builder.finalize().expect("fs image");
+    let fs = FileSystem::mount(RamStorage::new(image)).expect("filesystem mount");
+
+    let files = fs.files_rev();
+    assert_eq!(files.len(), 5);
+
+    let contents: Vec<Vec<u8>> = files
+        .map(|file| {
+            let mut file = file.expect("file open");
+            let mut buf = vec![0; file.total_size()];
+            file.read(&mut buf).expect("file read");
+            buf
+        })
+        .collect();
+    let mut expected: Vec<Vec<u8>> = fs
+        .file_indices()
+        .map(|index| read_full_file(&fs, index.into()))
+        .collect();
+    expected.reverse();
+    assert_eq!(contents, expected);
+
+    let builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    let image = builder.finalize().expect("fs image");
+    let fs = FileSystem::mount(RamStorage::new(image)).expect("filesystem mount");
+    assert_eq!(fs.files_rev().len(), 0);
+}
+
+#[test]
+fn test_mount_max_files() {
+    let mut writer = BytesMut::new();
+    let mut header = FilesystemHeader {
+        signature: simplefs::SIGNATURE,
+        version: FORMAT_VERSION,
+        num_files: u16::MAX,
+        dir_entry_size: size_of::<DirEntry>() as u16,
+        image_crc: 0,
+        build_time: 0,
+        header_crc: 0,
+        flags: 0,
+        dir_offset: 0,
+        dir_crc: 0,
+        largest_file: 0,
+        image_size: 0,
+    };
+    header.header_crc = header.crc();
+    header.to_bytes(&mut writer);
+    writer.resize(CAPACITY * 4, 0);
+
+    let storage = RecordingStorage::new(writer.freeze());
+    assert_eq!(
+        FileSystem::mount_with(&storage, MountOptions::new().max_files(16)).err(),
+        Some(Error::TooManyFiles)
+    );
+    // Only the header was read
+    assert_eq!(
+        *storage.reads.borrow(),
+        vec![0..size_of::<FilesystemHeader>()]
+    );
+
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    for _ in 0..3 {
+        builder.add_file(vec![1; 10]);
+    }
+    let image = builder.finalize().expect("fs image");
+    assert_eq!(
+        FileSystem::mount_with(image.as_ref(), MountOptions::new().max_files(2)).err(),
+        Some(Error::TooManyFiles)
+    );
+    let fs = FileSystem::mount_with(image.as_ref(), MountOptions::new().max_files(3))
+        .expect("filesystem mount");
+    assert_eq!(fs.get_num_files(), 3);
+}
+
+#[test]
+fn test_fragmentation() {
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.add_file(vec![1; 100]);
+    builder.add_file(vec![2; 100]);
+    let image = builder.finalize().expect("fs image");
+    let fs = FileSystem::mount(RamStorage::new(image)).expect("filesystem mount");
+    assert_eq!(
+        fs.fragmentation(&mut [DirEntry::default(); 8]),
+        Ok(FragInfo::default())
+    );
+
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.add_file(vec![1; 100]);
+    builder.add_file_at(0x1000, vec![2; 100]);
+    builder.add_file_at(0x2000, vec![3; 100]);
+    builder.add_file(vec![]);
+    let image = builder.finalize().expect("fs image");
+    let fs = FileSystem::mount(RamStorage::new(image)).expect("filesystem mount");
+    let data_start = dir_entry_offset(4);
+    assert_eq!(
+        fs.fragmentation(&mut [DirEntry::default(); 8]),
+        Ok(FragInfo {
+            gaps: 2,
+            gap_bytes: 0x1000 - (data_start + 100) + 0x2000 - 0x1064,
+        })
+    );
+    assert_eq!(
+        fs.fragmentation(&mut [DirEntry::default(); 3]),
+        Err(Error::BufferTooSmall)
+    );
+}
+
+#[test]
+fn test_file_flags() {
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.add_file_with_flags(vec![1; 10], 0x8000_0001);
+    builder.add_file(vec![2; 10]);
+    let image = builder.finalize().expect("fs image");
+    let fs = FileSystem::mount(RamStorage::new(image)).expect("filesystem mount");
+
+    let (file, direntry) = fs.open_with_entry(0).expect("file open");
+    assert_eq!(file.flags(), 0x8000_0001);
+    assert_eq!({ direntry.flags }, 0x8000_0001);
+    assert_eq!(fs.open(1).expect("file open").flags(), 0);
+
+    let repacked = SimpleFsBuilder::from_image(&fs)
+        .expect("image read")
+        .finalize()
+        .expect("fs image");
+    let fs = FileSystem::mount(RamStorage::new(repacked)).expect("filesystem mount");
+    assert_eq!(fs.open(0).expect("file open").flags(), 0x8000_0001);
+
+    // Entries written before flags were added read as no flags
+    let fs = FileSystem::mount(RamStorage::new(Bytes::from_static(EMBEDDED_IMAGE)))
+        .expect("filesystem mount");
+    assert_eq!(fs.open(0).expect("file open").flags(), 0);
+}
+
+#[test]
+fn test_read_split() {
+    let data: Vec<u8> = (0..100).collect();
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.add_file(data.clone());
+    let image = builder.finalize().expect("fs image");
+    let fs = FileSystem::mount(RecordingStorage::new(image)).expect("filesystem mount");
+
+    let mut file = fs.open(0).expect("file open");
+    let mut first = [0; 40];
+    let mut second = [0; 40];
+    fs.storage().reads.borrow_mut().clear();
+    assert_eq!(file.read_split(&mut first, &mut second), Ok(80));
+    assert_eq!(first, data[..40]);
+    assert_eq!(second, data[40..80]);
+    assert_eq!(fs.storage().reads.borrow().len(), 2);
+
+    // File ends in the second buffer
+    assert_eq!(file.read_split(&mut first[..10], &mut second), Ok(20));
+    assert_eq!(first[..10], data[80..90]);
+    assert_eq!(second[..10], data[90..]);
+
+    // File ends in the first buffer
+    let mut file = fs.open(0).expect("file open");
+    let mut first = [0; 150];
+    second.fill(0xff);
+    assert_eq!(file.read_split(&mut first, &mut second), Ok(100));
+    assert_eq!(first[..100], data);
+    assert_eq!(second, [0xff; 40]);
+}
+
+#[test]
+fn test_mount_lenient() {
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.add_file(vec![1; 10]);
+    builder.add_file(vec![2; 20]);
+    builder.add_file(vec![3; 30]);
+    let mut image = builder.finalize().expect("fs image").to_vec();
+
+    // Point the second entry past the end of the image
+    image[dir_entry_offset(1)..][..4].copy_from_slice(&0x10000000u32.to_be_bytes());
+    assert_eq!(
+        FileSystem::mount(RamStorage::new(Bytes::from(image.clone()))).err(),
+        Some(Error::DirectoryChecksumMismatch)
+    );
+
+    let fs = FileSystem::mount_lenient(RamStorage::new(Bytes::from(image.clone())))
+        .expect("filesystem mount");
+    assert_eq!(fs.get_num_files(), 3);
+    assert_eq!(read_full_file(&fs, 0), vec![1; 10]);
+    assert_eq!(fs.open(1).err(), Some(Error::CorruptedEntry));
+    assert_eq!(read_full_file(&fs, 2), vec![3; 30]);
+
+    // Entries pointing into the header or directory are also rejected
+    image[dir_entry_offset(1)..][..4].copy_from_slice(&0u32.to_be_bytes());
+    let fs = FileSystem::mount_lenient(RamStorage::new(Bytes::from(image.clone())))
+        .expect("filesystem mount");
+    assert_eq!(fs.open(1).err(), Some(Error::CorruptedEntry));
+
+    image[0] = b'X';
+    assert_eq!(
+        FileSystem::mount_lenient(RamStorage::new(Bytes::from(image))).err(),
+        Some(Error::InvalidSignature)
+    );
+}
+
+#[test]
+fn test_read_aligned() {
+    let data: Vec<u8> = (0..100).collect();
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.add_file(data.clone());
+    let image = builder.finalize().expect("fs image");
+    let fs = FileSystem::mount(RamStorage::new(image)).expect("filesystem mount");
+
+    #[repr(align(16))]
+    struct Aligned([u8; 64]);
+    let mut aligned = Aligned([0; 64]);
+
+    // Starts 3 bytes past a 16-byte boundary
+    let buf = &mut aligned.0[3..];
+    let mut file = fs.open(0).expect("file open");
+    let range = file.read_aligned(buf, 16).expect("file read");
+    assert_eq!(range, 13..61);
+    assert_eq!(buf.as_ptr() as usize % 16, 3);
+    assert_eq!(buf[range], data[..48]);
+
+    let range = file.read_aligned(&mut aligned.0, 16).expect("file read");
+    assert_eq!(range, 0..52);
+    assert_eq!(aligned.0[range], data[48..]);
+
+    // No aligned part
+    let mut file = fs.open(0).expect("file open");
+    let range = file
+        .read_aligned(&mut aligned.0[1..8], 16)
+        .expect("file read");
+    assert!(range.is_empty());
+    assert_eq!(file.read_pod::<1>(), Ok([0]));
+}
+
+#[test]
+fn test_file_region_bytes() {
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.add_file(vec![1, 2, 3]);
+    builder.add_sparse_file(vec![4; 10], 100);
+
+    let image_bytes = builder.finalize().expect("fs image");
+    let fs = FileSystem::mount(BytesStorage::new(image_bytes.clone())).expect("filesystem mount");
+
+    let region = fs.file_region_bytes(0).expect("file bytes");
+    assert_eq!(region, vec![1, 2, 3]);
+    let offset = dir_entry_offset(2);
+    assert_eq!(region.as_ptr(), image_bytes[offset..].as_ptr());
+
+    // Only the stored part of a sparse file
+    let region = fs.file_region_bytes(1).expect("file bytes");
+    assert_eq!(region, vec![4; 10]);
+    assert_eq!(region.as_ptr(), image_bytes[offset + 3..].as_ptr());
+
+    assert_eq!(fs.file_region_bytes(2), Err(Error::InvalidFileIndex));
+}
+
+#[test]
+fn test_read_adaptive() {
+    // Fails reads longer than MAX_READ bytes
+    struct BurstStorage(RamStorage);
+    const MAX_READ: usize = 64;
+
+    impl Storage for BurstStorage {
+        type Error = RamStorageError;
+
+        fn read(&self, off: usize, buf: &mut [u8]) -> Result<(), Self::Error> {
+            if buf.len() > MAX_READ {
+                return Err(RamStorageError::OutOfBoundsAccess);
+            }
+            self.0.read(off, buf)
+        }
+
+        fn capacity(&self) -> usize {
+            self.0.capacity()
+        }
+    }
+
+    let data: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.add_file(data.clone());
+    let image = builder.finalize().expect("fs image");
+    let fs = FileSystem::mount(BurstStorage(RamStorage::new(image))).expect("filesystem mount");
+
+    let mut buf = vec![0; 2000];
+    let mut file = fs.open(0).expect("file open");
+    assert_eq!(
+        file.read(&mut buf[..200]),
+        Err(Error::Storage(RamStorageError::OutOfBoundsAccess))
+    );
+
+    let mut file = fs.open(0).expect("file open");
+    assert_eq!(file.read_adaptive(&mut buf[..10], MAX_READ), Ok(10));
+    assert_eq!(file.read_adaptive(&mut buf[10..], MAX_READ), Ok(990));
+    assert_eq!(buf[..1000], data);
+    assert_eq!(file.read_adaptive(&mut buf, MAX_READ), Ok(0));
+}
+
+#[test]
+fn test_directory_size() {
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder
+        .set_directory_alignment(64)
+        .expect("directory alignment");
+    builder.add_file(vec![1; 10]);
+    builder.add_file(vec![2; 10]);
+    builder.add_file(vec![3; 10]);
+    let image = builder.finalize().expect("fs image");
+    let fs = FileSystem::mount(RamStorage::new(image)).expect("filesystem mount");
+
+    // File data starts right after the directory
+    assert_eq!(fs.directory_size(), 3 * size_of::<DirEntry>());
+    assert_eq!(
+        fs.read_dir_entry(0).expect("dir entry").offset as usize,
+        64 + fs.directory_size()
+    );
+
+    let builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    let image = builder.finalize().expect("fs image");
+    let fs = FileSystem::mount(RamStorage::new(image)).expect("filesystem mount");
+    assert_eq!(fs.directory_size(), 0);
+}
+
+#[test]
+fn test_callback_storage() {
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.add_file(vec![1, 2, 3]);
+    builder.add_file(vec![4; 100]);
+    let image = builder.finalize().expect("fs image");
+
+    let reads = RefCell::new(0);
+    let storage = CallbackStorage::new(image.len(), |off, buf: &mut [u8]| {
+        *reads.borrow_mut() += 1;
+        buf.copy_from_slice(&image[off..off + buf.len()]);
+        Ok::<(), RamStorageError>(())
+    });
+    let fs = FileSystem::mount(storage).expect("filesystem mount");
+    assert_eq!(fs.get_num_files(), 2);
+    assert_eq!(fs.open(0).expect("file open").equals(&[1, 2, 3]), Ok(true));
+    assert_eq!(fs.open(1).expect("file open").equals(&[4; 100]), Ok(true));
+    assert!(*reads.borrow() > 0);
+
+    let storage = CallbackStorage::new(image.len(), |_, _: &mut [u8]| {
+        Err(RamStorageError::OutOfBoundsAccess)
+    });
+    assert_eq!(
+        FileSystem::mount(storage).err(),
+        Some(Error::Storage(RamStorageError::OutOfBoundsAccess))
+    );
+}
+
+#[test]
+fn test_file_alignment() {
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.add_file(vec![1; 3]);
+    builder
+        .add_file_aligned(vec![2; 100], 64)
+        .expect("aligned file");
+    builder.add_file(vec![3; 5]);
+    builder
+        .add_file_aligned(vec![4; 10], 4096)
+        .expect("aligned file");
+    // Rounded up to 16
+    builder
+        .add_file_aligned(vec![5; 10], 10)
+        .expect("aligned file");
+    // No power of two to round up to
+    let alignment = usize::MAX / 2 + 2;
+    assert_eq!(
+        builder.add_file_aligned(vec![6; 10], alignment),
+        Err(BuilderError::AlignmentTooLarge { alignment })
+    );
+    let image = builder.finalize().expect("fs image");
+    let fs = FileSystem::mount_eager(
+        RamStorage::new(image.clone()),
+        &mut [DirEntry::default(); 8],
+    )
+    .expect("filesystem mount");
+
+    let offsets: Vec<usize> = (0..5)
+        .map(|index| fs.open(index).expect("file open").storage_offset())
+        .collect();
+    assert_eq!(offsets[0], dir_entry_offset(5));
+    assert_eq!(offsets[1] % 64, 0);
+    // Plain files are packed right after the previous one
+    assert_eq!(offsets[2], offsets[1] + 100);
+    assert_eq!(offsets[3] % 4096, 0);
+    assert_eq!(offsets[4], offsets[3] + 16);
+    assert_eq!(
+        fs.read_dir_entry(4).expect("dir entry").alignment(),
+        Some(16)
+    );
+    for (index, offset) in offsets.iter().enumerate() {
+        let mut file = fs.open(index).expect("file open");
+        let mut byte = [0];
+        file.read(&mut byte).expect("file read");
+        assert_eq!(byte[0], index as u8 + 1);
+        assert_eq!(image[*offset], index as u8 + 1);
+    }
+
+    // Misaligned entry is rejected
+    let mut image = image.to_vec();
+    let entry_offset = dir_entry_offset(1);
+    image[entry_offset..][..4].copy_from_slice(&(offsets[1] as u32 + 1).to_be_bytes());
+    update_directory_crc(&mut image);
+    assert_eq!(
+        FileSystem::validate_prefix(&image[..]),
+        Err(Error::CorruptedFileSystem)
+    );
+    let fs = FileSystem::mount(RamStorage::new(Bytes::from(image))).expect("filesystem mount");
+    assert_eq!(fs.open(1).err(), Some(Error::CorruptedFileSystem));
+}
+
+#[test]
+fn test_open_scoped() {
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.add_file(vec![1, 2, 3, 4]);
+    let image = builder.finalize().expect("fs image");
+    let mut fs = FileSystem::mount(RamStorage::new(image)).expect("filesystem mount");
+
+    let sum = fs.open_scoped(0, |mut file| {
+        let mut buf = [0; 4];
+        file.read(&mut buf).expect("file read");
+        buf.iter().map(|&byte| byte as u32).sum::<u32>()
+    });
+    assert_eq!(sum, Ok(10));
+
+    // Filesystem is no longer borrowed
+    fs.set_max_file_size(2);
+    assert_eq!(
+        fs.open_scoped(0, |file| file.total_size()),
+        Err(Error::FileTooLarge)
+    );
+}
+
+#[test]
+fn test_rom_storage() {
+    const ROM: RomStorage = RomStorage::new(EMBEDDED_IMAGE, 0x0800_0000);
+
+    let fs = FileSystem::mount(ROM).expect("filesystem mount");
+    assert_eq!(fs.get_num_files(), 1);
+    let mut file = fs.open(0).expect("file open");
+    assert_eq!(file.read_pod::<3>(), Ok([1, 2, 3]));
+
+    let address = fs.storage().address(file.storage_offset());
+    assert_eq!(address, Some(0x0800_0000 + EMBEDDED_IMAGE.len() - 3));
+    assert_eq!(fs.storage().base(), 0x0800_0000);
+
+    let top = RomStorage::new(EMBEDDED_IMAGE, usize::MAX - 1);
+    assert_eq!(top.address(1), Some(usize::MAX));
+    assert_eq!(top.address(2), None);
+}
+
+#[cfg(feature = "heapless")]
+#[test]
+fn test_read_to_arrayvec() {
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.add_file(vec![1, 2, 3]);
+    builder.add_file(vec![]);
+    builder.add_sparse_file(vec![4; 10], 16);
+    let image = builder.finalize().expect("fs image");
+    let fs = FileSystem::mount(RamStorage::new(image)).expect("filesystem mount");
+
+    let mut file = fs.open(0).expect("file open");
+    let contents = file.read_to_arrayvec::<16>().expect("file read");
+    assert_eq!(&contents[..], &[1, 2, 3]);
+
+    let mut file = fs.open(1).expect("file open");
+    assert!(file.read_to_arrayvec::<16>().expect("file read").is_empty());
+
+    let mut file = fs.open(2).expect("file open");
+    let mut expected = [0; 16];
+    expected[..10].fill(4);
+    assert_eq!(
+        &file.read_to_arrayvec::<16>().expect("file read")[..],
+        &expected
+    );
+
+    let mut file = fs.open(2).expect("file open");
+    assert_eq!(
+        file.read_to_arrayvec::<8>().err(),
+        Some(Error::BufferTooSmall)
+    );
+}
+
+#[test]
+fn test_image_too_large_for_format() {
+    let offset = u32::MAX as usize - 7;
+
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(usize::MAX);
+    builder.add_file_at(offset, vec![1; 16]);
+    assert_eq!(
+        builder.finalize().err(),
+        Some(BuilderError::ImageTooLargeForFormat { size: offset + 16 })
+    );
+
+    // Data ending right past the last u32 offset
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(usize::MAX);
+    builder.add_file(vec![2; 8]);
+    builder.add_file_at(offset, vec![1; 8]);
+    assert_eq!(
+        builder.finalize().err(),
+        Some(BuilderError::ImageTooLargeForFormat {
+            size: u32::MAX as usize + 1
+        })
+    );
+
+    // Capacity is checked first
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.add_file_at(offset, vec![1; 16]);
+    assert_eq!(
+        builder.finalize().err(),
+        Some(BuilderError::OutOfSpace {
+            needed: offset + 16,
+            capacity: CAPACITY
+        })
+    );
+}
+
+#[test]
+fn test_read_xored() {
+    let key = b"secret";
+    let data: Vec<u8> = (0..100).collect();
+    let masked: Vec<u8> = data
+        .iter()
+        .enumerate()
+        .map(|(position, byte)| byte ^ key[position % key.len()])
+        .collect();
+
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.add_file(masked.clone());
+    let image = builder.finalize().expect("fs image");
+    let fs = FileSystem::mount(RamStorage::new(image)).expect("filesystem mount");
+
+    // Chunks not aligned to key length
+    let mut file = fs.open(0).expect("file open");
+    let mut contents = Vec::new();
+    let mut buf = [0; 7];
+    loop {
+        let len = file.read_xored(&mut buf, key).expect("file read");
+        if len == 0 {
+            break;
+        }
+        contents.extend_from_slice(&buf[..len]);
+    }
+    assert_eq!(contents, data);
+
+    let mut file = fs.open(0).expect("file open");
+    let mut buf = [0; 100];
+    assert_eq!(file.read_xored(&mut buf, b""), Ok(100));
+    assert_eq!(buf[..], masked);
+}
+
+#[test]
+fn test_largest_file_size() {
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.add_file(vec![1; 100]);
+    builder.add_file(vec![2; 3000]);
+    builder.add_file(vec![3; 10]);
+    let image = builder.finalize().expect("fs image");
+    let fs = FileSystem::mount(RamStorage::new(image)).expect("filesystem mount");
+    assert_eq!(fs.largest_file_size(), 3000);
+
+    let largest = fs
+        .file_indices()
+        .map(|index| fs.open_index(index).expect("file open").total_size())
+        .max();
+    assert_eq!(Some(fs.largest_file_size()), largest);
+
+    // Sparse files count with their zero-filled tail
+    let mut builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    builder.add_file(vec![1; 100]);
+    builder.add_sparse_file(vec![2; 10], 5000);
+    let image = builder.finalize().expect("fs image");
+    let fs = FileSystem::mount(RamStorage::new(image)).expect("filesystem mount");
+    assert_eq!(fs.largest_file_size(), 5000);
+
+    let builder: SimpleFsBuilder = SimpleFsBuilder::new(CAPACITY);
+    let image = builder.finalize().expect("fs image");
+    let fs = FileSystem::mount(RamStorage::new(image)).expect("filesystem mount");
+    assert_eq!(fs.largest_file_size(), 0);
+
+    let mut buf = [0; 256];
+    let mut builder = CoreBuilder::new(&mut buf, 2).expect("core builder");
+    builder.add_file(&[1; 4]).expect("add file");
+    builder.add_file(&[2; 7]).expect("add file");
+    let size = builder.finalize();
+    let fs = FileSystem::mount(&buf[..size]).expect("filesystem mount");
+    assert_eq!(fs.largest_file_size(), 7);
+}
@@ -1,186 +1,2048 @@
 #![no_std]
 #![deny(unsafe_code)]
 
+#[cfg(feature = "std")]
+extern crate std;
+
 use bytes::{Buf, BufMut};
+use core::convert::Infallible;
+use core::marker::PhantomData;
 use core::mem::size_of;
+use core::ops::Range;
+
+// Backend storage API. Originally from littlefs2 crate.
+pub trait Storage {
+    // Error type
+    type Error;
+
+    // Total storage size in bytes.
+    fn capacity(&self) -> usize;
+
+    // Read data from the storage device.
+    // Guaranteed not to be called with off > capacity() or bufs of length > capacity() - off.
+    fn read(&self, off: usize, buf: &mut [u8]) -> Result<(), Self::Error>;
+}
+
+/// Error of in-memory storage, returned for reads past the end of data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutOfBounds;
+
+fn read_slice(data: &[u8], off: usize, buf: &mut [u8]) -> Result<(), OutOfBounds> {
+    let end = off.checked_add(buf.len()).ok_or(OutOfBounds)?;
+    buf.copy_from_slice(data.get(off..end).ok_or(OutOfBounds)?);
+    Ok(())
+}
+
+/// Mount an image from a borrowed buffer.
+///
+/// ```
+/// use simplefs::FileSystem;
+///
+/// const IMAGE: &[u8] = b"SimpleFS\x00\x01\x00\x00\x00\x31\xe9\xfd\x95\xc4\x00\x00\x00\x00\x00\x00\x00\x00\x65\x8a\x88\x32\x00\x00\x00\x00\x00\x00\x00\x32\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x32";
+/// let fs = FileSystem::mount(IMAGE).unwrap();
+/// assert_eq!(fs.get_num_files(), 0);
+/// ```
+impl Storage for &[u8] {
+    type Error = OutOfBounds;
+
+    fn capacity(&self) -> usize {
+        self.len()
+    }
+
+    fn read(&self, off: usize, buf: &mut [u8]) -> Result<(), Self::Error> {
+        read_slice(self, off, buf)
+    }
+}
+
+/// Mount an image from a mutably borrowed buffer.
+///
+/// ```
+/// use simplefs::FileSystem;
+///
+/// let mut image = *b"SimpleFS\x00\x01\x00\x00\x00\x31\xe9\xfd\x95\xc4\x00\x00\x00\x00\x00\x00\x00\x00\x65\x8a\x88\x32\x00\x00\x00\x00\x00\x00\x00\x32\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x32";
+/// let fs = FileSystem::mount(&mut image[..]).unwrap();
+/// assert_eq!(fs.get_num_files(), 0);
+/// ```
+impl Storage for &mut [u8] {
+    type Error = OutOfBounds;
+
+    fn capacity(&self) -> usize {
+        self.len()
+    }
+
+    fn read(&self, off: usize, buf: &mut [u8]) -> Result<(), Self::Error> {
+        read_slice(self, off, buf)
+    }
+}
+
+/// Mount an image from an owned array.
+///
+/// ```
+/// use simplefs::FileSystem;
+///
+/// let image = *b"SimpleFS\x00\x01\x00\x00\x00\x31\xe9\xfd\x95\xc4\x00\x00\x00\x00\x00\x00\x00\x00\x65\x8a\x88\x32\x00\x00\x00\x00\x00\x00\x00\x32\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x32";
+/// let fs = FileSystem::mount(image).unwrap();
+/// assert_eq!(fs.get_num_files(), 0);
+/// ```
+impl<const N: usize> Storage for [u8; N] {
+    type Error = OutOfBounds;
+
+    fn capacity(&self) -> usize {
+        N
+    }
+
+    fn read(&self, off: usize, buf: &mut [u8]) -> Result<(), Self::Error> {
+        read_slice(self, off, buf)
+    }
+}
+
+// Image in memory-mapped ROM or flash, linked at a known address. Offsets are relative
+// to the start of the image, address() translates them to bus addresses, e.g. for
+// handing file data to DMA.
+#[derive(Debug, Clone, Copy)]
+pub struct RomStorage {
+    data: &'static [u8],
+    base: usize,
+}
+
+impl RomStorage {
+    // base is the address of data[0]
+    pub const fn new(data: &'static [u8], base: usize) -> Self {
+        Self { data, base }
+    }
+
+    pub fn base(&self) -> usize {
+        self.base
+    }
+
+    // Address of the byte at storage offset off, None if it is past the end of the
+    // address space
+    pub fn address(&self, off: usize) -> Option<usize> {
+        self.base.checked_add(off)
+    }
+}
+
+impl Storage for RomStorage {
+    type Error = OutOfBounds;
+
+    fn capacity(&self) -> usize {
+        self.data.len()
+    }
+
+    fn read(&self, off: usize, buf: &mut [u8]) -> Result<(), Self::Error> {
+        read_slice(self.data, off, buf)
+    }
+}
+
+// Two backends presented as one, e.g. an image split across two flash banks.
+// Offsets below first.capacity() go to first, the rest to second.
+#[derive(Debug, Clone)]
+pub struct ChainStorage<A, B> {
+    first: A,
+    second: B,
+}
+
+impl<A, B> ChainStorage<A, B> {
+    pub fn new(first: A, second: B) -> Self {
+        Self { first, second }
+    }
+
+    pub fn into_inner(self) -> (A, B) {
+        (self.first, self.second)
+    }
+}
+
+impl<A: Storage, B: Storage<Error = A::Error>> Storage for ChainStorage<A, B> {
+    type Error = A::Error;
+
+    fn capacity(&self) -> usize {
+        self.first.capacity() + self.second.capacity()
+    }
+
+    fn read(&self, off: usize, buf: &mut [u8]) -> Result<(), Self::Error> {
+        let first_capacity = self.first.capacity();
+        if off >= first_capacity {
+            return self.second.read(off - first_capacity, buf);
+        }
+
+        // Split reads that straddle the boundary
+        let (head, tail) = buf.split_at_mut((first_capacity - off).min(buf.len()));
+        self.first.read(off, head)?;
+        if !tail.is_empty() {
+            self.second.read(0, tail)?;
+        }
+
+        Ok(())
+    }
+}
+
+// Storage over a read function, for sources that don't warrant their own type.
+// read is called with the same guarantees as Storage::read.
+pub struct CallbackStorage<F, E> {
+    capacity: usize,
+    read: F,
+    _error: PhantomData<E>,
+}
+
+impl<F: Fn(usize, &mut [u8]) -> Result<(), E>, E> CallbackStorage<F, E> {
+    pub fn new(capacity: usize, read: F) -> Self {
+        Self {
+            capacity,
+            read,
+            _error: PhantomData,
+        }
+    }
+}
+
+impl<F: Fn(usize, &mut [u8]) -> Result<(), E>, E> Storage for CallbackStorage<F, E> {
+    type Error = E;
+
+    fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    fn read(&self, off: usize, buf: &mut [u8]) -> Result<(), Self::Error> {
+        (self.read)(off, buf)
+    }
+}
+
+// Refcounted in-memory image, allows zero-copy access to file contents.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone)]
+pub struct BytesStorage {
+    bytes: bytes::Bytes,
+}
+
+#[cfg(feature = "std")]
+impl BytesStorage {
+    pub fn new(bytes: bytes::Bytes) -> Self {
+        Self { bytes }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Storage for BytesStorage {
+    type Error = OutOfBounds;
+
+    fn capacity(&self) -> usize {
+        self.bytes.len()
+    }
+
+    fn read(&self, off: usize, buf: &mut [u8]) -> Result<(), Self::Error> {
+        read_slice(&self.bytes, off, buf)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Error<E> {
+    InvalidSignature,
+    CorruptedFileSystem,
+    InvalidFileIndex,
+    FileTooLarge,
+    BufferTooSmall,
+    HeaderChecksumMismatch,
+    UnexpectedEof,
+    Interrupted { bytes_read: usize },
+    UnsupportedFeature,
+    InvalidUtf8,
+    UnexpectedTrailingData,
+    DirectoryChecksumMismatch,
+    MagicMismatch,
+    TooManyFiles,
+    CorruptedEntry,
+    UnsupportedVersion,
+    Storage(E),
+}
+
+impl<E> From<E> for Error<E> {
+    fn from(error: E) -> Self {
+        Error::Storage(error)
+    }
+}
+
+// Index of a file in the directory. Images hold at most u16::MAX files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FileIndex(pub u16);
+
+impl From<u16> for FileIndex {
+    fn from(index: u16) -> Self {
+        FileIndex(index)
+    }
+}
+
+impl From<FileIndex> for usize {
+    fn from(index: FileIndex) -> Self {
+        index.0 as usize
+    }
+}
+
+impl TryFrom<usize> for FileIndex {
+    type Error = core::num::TryFromIntError;
+
+    fn try_from(index: usize) -> Result<Self, Self::Error> {
+        Ok(FileIndex(index.try_into()?))
+    }
+}
+
+pub struct FileSystem<S> {
+    storage: S,
+    num_files: u16,
+    dir_offset: usize,
+    dir_entry_size: usize,
+    // End of the primary directory
+    data_start: usize,
+    build_time: u64,
+    version: u16,
+    flags: HeaderFlags,
+    needs_repair: bool,
+    max_file_size: usize,
+    // Mounted with mount_lenient, open() checks entries individually
+    lenient: bool,
+    largest_file_size: usize,
+    image_size: usize,
+}
+
+// Read and validate filesystem header at the given storage offset
+fn read_header<S: Storage>(
+    storage: &S,
+    offset: usize,
+) -> Result<FilesystemHeader, Error<S::Error>> {
+    if storage.capacity() < offset + size_of::<FilesystemHeader>() {
+        return Err(Error::CorruptedFileSystem);
+    }
+
+    let mut buf = [0; size_of::<FilesystemHeader>()];
+    storage.read(offset, &mut buf)?;
+    let header =
+        FilesystemHeader::from_bytes(&mut buf.as_slice()).ok_or(Error::CorruptedFileSystem)?;
+
+    if header.signature != SIGNATURE {
+        return Err(Error::InvalidSignature);
+    }
+
+    // Checked before the CRC, since other versions may lay out the header differently
+    if header.version != FORMAT_VERSION {
+        return Err(Error::UnsupportedVersion);
+    }
+
+    let header_crc = header.header_crc;
+    if header_crc != header.crc() {
+        return Err(Error::HeaderChecksumMismatch);
+    }
+
+    // Entries may be larger than DirEntry if written by a newer builder,
+    // or smaller if written by an older one, but must contain the base fields.
+    if (header.dir_entry_size as usize) < DirEntry::MIN_SIZE {
+        return Err(Error::CorruptedFileSystem);
+    }
+
+    if header.dir_offset != 0 && (header.dir_offset as usize) < size_of::<FilesystemHeader>() {
+        return Err(Error::CorruptedFileSystem);
+    }
+
+    let flags = HeaderFlags::from_bits(header.flags);
+    if flags.is_compressed() || flags.is_encrypted() {
+        return Err(Error::UnsupportedFeature);
+    }
+
+    Ok(header)
+}
+
+// Optional checks done by FileSystem::mount_with
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MountOptions {
+    strict_size: bool,
+    max_files: Option<u16>,
+}
+
+impl MountOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Require storage to end exactly where the image does, to catch images
+    // concatenated with other data. By default, trailing bytes are ignored.
+    pub fn strict_size(mut self, strict_size: bool) -> Self {
+        self.strict_size = strict_size;
+        self
+    }
+
+    // Reject images with more than max_files files before reading the directory, to
+    // bound the cost of mounting untrusted images. Unlimited by default.
+    pub fn max_files(mut self, max_files: u16) -> Self {
+        self.max_files = Some(max_files);
+        self
+    }
+}
+
+fn check_num_files<E>(header: &FilesystemHeader, max_files: Option<u16>) -> Result<(), Error<E>> {
+    match max_files {
+        Some(max_files) if header.num_files > max_files => Err(Error::TooManyFiles),
+        _ => Ok(()),
+    }
+}
+
+// Check that the directory at dir_offset fits in storage and matches the header CRC
+fn check_directory<S: Storage>(
+    storage: &S,
+    header: &FilesystemHeader,
+    dir_offset: usize,
+) -> Result<(), Error<S::Error>> {
+    let dir_end = header
+        .dir_size()
+        .and_then(|dir_size| dir_size.checked_add(dir_offset))
+        .ok_or(Error::CorruptedFileSystem)?;
+    if storage.capacity() < dir_end {
+        return Err(Error::CorruptedFileSystem);
+    }
+
+    let mut crc = Crc32::new();
+    let mut buf = [0; 64];
+    let mut position = dir_offset;
+    while position < dir_end {
+        let chunk = &mut buf[..(dir_end - position).min(64)];
+        storage.read(position, chunk)?;
+        crc.update(chunk);
+        position += chunk.len();
+    }
+
+    let dir_crc = header.dir_crc;
+    if crc.finalize() != dir_crc {
+        return Err(Error::DirectoryChecksumMismatch);
+    }
+
+    Ok(())
+}
+
+impl<S: Storage> FileSystem<S> {
+    // If the header or directory at the start of storage is damaged, the filesystem is
+    // mounted from the backup copy at the end of storage instead, if the image has one.
+    pub fn mount(storage: S) -> Result<Self, Error<S::Error>> {
+        Self::mount_limited(storage, None)
+    }
+
+    fn mount_limited(storage: S, max_files: Option<u16>) -> Result<Self, Error<S::Error>> {
+        let primary = read_header(&storage, 0).and_then(|header| {
+            check_num_files(&header, max_files)?;
+            check_directory(&storage, &header, header.dir_start())?;
+            Ok(header)
+        });
+        let header = match primary {
+            Ok(header) => header,
+            Err(Error::Storage(error)) => return Err(Error::Storage(error)),
+            // Header is intact, the backup copy would have the same file count
+            Err(Error::TooManyFiles) => return Err(Error::TooManyFiles),
+            Err(error) => {
+                return Self::mount_backup(storage, max_files).map_err(|backup_error| {
+                    match backup_error {
+                        Error::Storage(error) => Error::Storage(error),
+                        // No usable backup, report what was wrong with the primary copy
+                        _ => error,
+                    }
+                });
+            }
+        };
+
+        let dir_offset = header.dir_start();
+        Ok(Self::new(storage, &header, dir_offset, false))
+    }
+
+    // Backup is the directory followed by the header, at the very end of storage.
+    // The builder pads such images to their capacity, so this is also the end of the image.
+    // Only the header and directory are copied. The copy stores image_crc as 0, so file
+    // data read through it is not checked against anything.
+    fn mount_backup(storage: S, max_files: Option<u16>) -> Result<Self, Error<S::Error>> {
+        let header_offset = storage
+            .capacity()
+            .checked_sub(size_of::<FilesystemHeader>())
+            .ok_or(Error::CorruptedFileSystem)?;
+        let header = read_header(&storage, header_offset)?;
+        // Images built without a backup may end with anything, including a valid header
+        if !HeaderFlags::from_bits(header.flags).has_backup_header() {
+            return Err(Error::CorruptedFileSystem);
+        }
+        check_num_files(&header, max_files)?;
+        if header.image_size as usize != storage.capacity() {
+            return Err(Error::CorruptedFileSystem);
+        }
+
+        let dir_offset = header
+            .dir_size()
+            .and_then(|dir_size| header_offset.checked_sub(dir_size))
+            .ok_or(Error::CorruptedFileSystem)?;
+        if dir_offset < header.dir_end().ok_or(Error::CorruptedFileSystem)? {
+            return Err(Error::CorruptedFileSystem);
+        }
+        check_directory(&storage, &header, dir_offset)?;
+
+        Ok(Self::new(storage, &header, dir_offset, true))
+    }
+
+    fn new(storage: S, header: &FilesystemHeader, dir_offset: usize, needs_repair: bool) -> Self {
+        FileSystem {
+            storage,
+            num_files: header.num_files,
+            dir_offset,
+            dir_entry_size: header.dir_entry_size as usize,
+            // Checked by the caller
+            data_start: header.dir_start()
+                + header.num_files as usize * header.dir_entry_size as usize,
+            build_time: header.build_time,
+            version: header.version,
+            flags: HeaderFlags::from_bits(header.flags),
+            needs_repair,
+            max_file_size: usize::MAX,
+            lenient: false,
+            largest_file_size: header.largest_file as usize,
+            image_size: header.image_size as usize,
+        }
+    }
+
+    // Mount an image with a damaged directory, for salvaging intact files. The header
+    // must be valid, but the directory checksum isn't checked. Instead open() fails with
+    // CorruptedEntry for entries that point outside of the data area.
+    pub fn mount_lenient(storage: S) -> Result<Self, Error<S::Error>> {
+        let header = read_header(&storage, 0)?;
+        let dir_offset = header.dir_start();
+        match check_directory(&storage, &header, dir_offset) {
+            Ok(()) | Err(Error::DirectoryChecksumMismatch) => {}
+            Err(error) => return Err(error),
+        }
+
+        let mut fs = Self::new(storage, &header, dir_offset, false);
+        fs.lenient = true;
+        Ok(fs)
+    }
+
+    pub fn mount_with(storage: S, options: MountOptions) -> Result<Self, Error<S::Error>> {
+        let fs = Self::mount_limited(storage, options.max_files)?;
+
+        if options.strict_size && fs.image_size()? != fs.storage.capacity() {
+            return Err(Error::UnexpectedTrailingData);
+        }
+
+        Ok(fs)
+    }
+
+    // Mount and validate every directory entry upfront, so that open() can't fail
+    // later because of a corrupted directory. Entries are read into scratch and sorted
+    // there to check that file data regions don't overlap, fails with BufferTooSmall
+    // if scratch can't hold the whole directory.
+    pub fn mount_eager(storage: S, scratch: &mut [DirEntry]) -> Result<Self, Error<S::Error>> {
+        let fs = Self::mount(storage)?;
+        let data_end = if fs.needs_repair {
+            fs.dir_offset
+        } else {
+            fs.storage.capacity()
+        };
+
+        // Regions come sorted by start, so each one only has to start past the furthest
+        // end seen so far
+        let mut covered_until = fs.data_start;
+        for direntry in fs.entries_by_offset(scratch)? {
+            let region = fs.file_region(direntry)?;
+            if region.start < covered_until || region.end > data_end {
+                return Err(Error::CorruptedFileSystem);
+            }
+            covered_until = covered_until.max(region.end);
+        }
+
+        Ok(fs)
+    }
+
+    // Unused space between the directory and the end of the last file. Entries are
+    // read into scratch and sorted there, fails with BufferTooSmall if scratch can't
+    // hold the whole directory.
+    pub fn fragmentation(&self, scratch: &mut [DirEntry]) -> Result<FragInfo, Error<S::Error>> {
+        let mut info = FragInfo::default();
+        let mut position = self.data_start;
+        for direntry in self.entries_by_offset(scratch)? {
+            let region = self.file_region(direntry)?;
+            if region.is_empty() {
+                continue;
+            }
+
+            if region.start > position {
+                info.gaps += 1;
+                info.gap_bytes += region.start - position;
+            }
+            position = position.max(region.end);
+        }
+
+        Ok(info)
+    }
+
+    // Image size as recorded by the builder: header, directory, file data, and for
+    // images with a backup copy, padding up to the builder capacity and the copy itself.
+    // Fails with CorruptedFileSystem if storage is too small to hold the image.
+    pub fn image_size(&self) -> Result<usize, Error<S::Error>> {
+        if self.image_size < self.data_start || self.image_size > self.storage.capacity() {
+            return Err(Error::CorruptedFileSystem);
+        }
+
+        Ok(self.image_size)
+    }
+
+    // Size of the directory in bytes, using the entry size stored in the header
+    pub fn directory_size(&self) -> usize {
+        self.num_files as usize * self.dir_entry_size
+    }
+
+    // Largest logical file size, as recorded by the builder. A buffer of this size can
+    // hold any file in the image.
+    pub fn largest_file_size(&self) -> usize {
+        self.largest_file_size
+    }
+
+    pub fn get_num_files(&self) -> u16 {
+        self.num_files
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.num_files == 0
+    }
+
+    // True if the primary header was damaged and the filesystem was mounted from the
+    // backup copy. Rewriting the image is recommended. The backup only covers the header
+    // and directory, file data may be damaged as well.
+    pub fn needs_repair(&self) -> bool {
+        self.needs_repair
+    }
+
+    // Image creation time as set by the builder, usually seconds since Unix epoch.
+    pub fn build_time(&self) -> u64 {
+        self.build_time
+    }
+
+    // Format version the image was written with. Only FORMAT_VERSION mounts for now.
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    pub fn flags(&self) -> HeaderFlags {
+        self.flags
+    }
+
+    // Make open() reject files larger than max bytes. Guards against untrusted images
+    // that may claim huge file sizes.
+    pub fn set_max_file_size(&mut self, max: usize) {
+        self.max_file_size = max;
+    }
+
+    // Backend storage the filesystem was mounted from.
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+
+    // Unmount the filesystem and return the backend storage.
+    pub fn into_storage(self) -> S {
+        self.storage
+    }
+
+    // Compute CRC32 of the whole image, as reported by image_size, excluding the stored
+    // CRC field itself. Storage past the end of the image is not included.
+    // Uses buf as scratch space for reading the storage.
+    pub fn compute_image_crc(&self, buf: &mut [u8]) -> Result<u32, Error<S::Error>> {
+        if buf.is_empty() {
+            return Err(Error::BufferTooSmall);
+        }
+
+        let image_size = self.image_size()?;
+        let mut crc = Crc32::new();
+        self.update_crc(&mut crc, 0..FilesystemHeader::IMAGE_CRC_OFFSET, buf)?;
+        self.update_crc(
+            &mut crc,
+            FilesystemHeader::IMAGE_CRC_OFFSET + size_of::<u32>()..image_size,
+            buf,
+        )?;
+
+        Ok(crc.finalize())
+    }
+
+    fn update_crc(
+        &self,
+        crc: &mut Crc32,
+        range: Range<usize>,
+        buf: &mut [u8],
+    ) -> Result<(), Error<S::Error>> {
+        let mut offset = range.start;
+        while offset < range.end {
+            let chunk_size = (range.end - offset).min(buf.len());
+            let chunk = &mut buf[..chunk_size];
+            self.storage.read(offset, chunk)?;
+            crc.update(chunk);
+            offset += chunk.len();
+        }
+
+        Ok(())
+    }
+
+    // Hash the whole image, as reported by image_size, with SHA-256 and compare it to
+    // expected. Unlike the image CRC this detects deliberate tampering, provided expected
+    // comes from a trusted source. buf is scratch space for reading storage.
+    #[cfg(feature = "sha256")]
+    pub fn verify_digest(&self, expected: &[u8], buf: &mut [u8]) -> Result<bool, Error<S::Error>> {
+        if buf.is_empty() {
+            return Err(Error::BufferTooSmall);
+        }
+
+        let image_size = self.image_size()?;
+        let mut hasher = Sha256::new();
+        let mut offset = 0;
+        while offset < image_size {
+            let chunk_size = (image_size - offset).min(buf.len());
+            let chunk = &mut buf[..chunk_size];
+            self.storage.read(offset, chunk)?;
+            hasher.update(chunk);
+            offset += chunk.len();
+        }
+
+        Ok(hasher.finalize() == expected)
+    }
+
+    // Open every file, in index order.
+    #[cfg(feature = "std")]
+    pub fn open_all(&self) -> Result<std::vec::Vec<File<S>>, Error<S::Error>> {
+        (0..self.num_files as usize)
+            .map(|index| self.open(index))
+            .collect()
+    }
+
+    // Fill buf with (index, length) pairs for all files, ordered by ascending length.
+    // Returns the filled part of buf.
+    pub fn indices_sorted_by_size<'b>(
+        &self,
+        buf: &'b mut [(usize, u32)],
+    ) -> Result<&'b [(usize, u32)], Error<S::Error>> {
+        let num_files = self.num_files as usize;
+        let buf = buf.get_mut(..num_files).ok_or(Error::BufferTooSmall)?;
+
+        for (index, item) in buf.iter_mut().enumerate() {
+            *item = (index, self.read_dir_entry(index)?.length);
+        }
+
+        buf.sort_unstable_by_key(|&(index, length)| (length, index));
+        Ok(buf)
+    }
+
+    // Fill out with (index, length) pairs of up to n largest files, ordered by descending
+    // length. Returns the number of pairs written.
+    pub fn top_files_by_size(
+        &self,
+        n: usize,
+        out: &mut [(usize, u32)],
+    ) -> Result<usize, Error<S::Error>> {
+        let n = n.min(out.len());
+        let mut count = 0;
+
+        for index in 0..self.num_files as usize {
+            let length = self.read_dir_entry(index)?.length;
+            // Ties keep the lower index first
+            let position = out[..count].partition_point(|&(_, other)| other >= length);
+            if position >= n {
+                continue;
+            }
+
+            count = (count + 1).min(n);
+            out[position..count].rotate_right(1);
+            out[position] = (index, length);
+        }
+
+        Ok(count)
+    }
+
+    pub fn open(&self, index: usize) -> Result<File<S>, Error<S::Error>> {
+        Ok(self.open_with_entry(index)?.0)
+    }
+
+    // Open a file and also return its directory entry, to get metadata without reading
+    // the directory twice.
+    pub fn open_with_entry(&self, index: usize) -> Result<(File<S>, DirEntry), Error<S::Error>> {
+        let direntry = self.read_dir_entry(index)?;
+        if self.lenient
+            && !self
+                .file_region(&direntry)
+                .is_ok_and(|region| region.start >= self.data_start)
+        {
+            return Err(Error::CorruptedEntry);
+        }
+        if direntry.logical_size() as usize > self.max_file_size {
+            return Err(Error::FileTooLarge);
+        }
+
+        self.file_region(&direntry)?;
+
+        Ok((File::new(&self.storage, &direntry), direntry))
+    }
+
+    // Open a file and check that it starts with magic. The returned file is positioned at
+    // the start, as if returned by open(). Fails with MagicMismatch if the file doesn't
+    // start with magic, including when it is shorter.
+    pub fn open_expecting_magic(
+        &self,
+        index: usize,
+        magic: &[u8],
+    ) -> Result<File<S>, Error<S::Error>> {
+        let file = self.open(index)?;
+
+        let mut probe = file.clone();
+        let mut buf = [0; 64];
+        for magic_chunk in magic.chunks(buf.len()) {
+            let chunk = &mut buf[..magic_chunk.len()];
+            if probe.read(chunk)? != chunk.len() || chunk != magic_chunk {
+                return Err(Error::MagicMismatch);
+            }
+        }
+
+        Ok(file)
+    }
+
+    // Open a file and pass it to f, returning what f returns. The file can't outlive
+    // the call, which keeps the borrow of the filesystem short.
+    pub fn open_scoped<R>(
+        &self,
+        index: usize,
+        f: impl FnOnce(File<S>) -> R,
+    ) -> Result<R, Error<S::Error>> {
+        Ok(f(self.open(index)?))
+    }
+
+    // Same as open(), with a typed index
+    pub fn open_index(&self, index: FileIndex) -> Result<File<S>, Error<S::Error>> {
+        self.open(index.into())
+    }
+
+    // Number of files whose directory entries match pred. Doesn't open the files.
+    pub fn count_where<F: FnMut(&DirEntry) -> bool>(
+        &self,
+        mut pred: F,
+    ) -> Result<usize, Error<S::Error>> {
+        let mut count = 0;
+        for index in 0..self.num_files as usize {
+            if pred(&self.read_dir_entry(index)?) {
+                count += 1;
+            }
+        }
+
+        Ok(count)
+    }
+
+    // Parse all directory entries into out and return the filled part, so that callers
+    // can iterate over the directory without further storage reads.
+    pub fn read_directory<'b>(
+        &self,
+        out: &'b mut [DirEntry],
+    ) -> Result<&'b [DirEntry], Error<S::Error>> {
+        let out = out
+            .get_mut(..self.num_files as usize)
+            .ok_or(Error::BufferTooSmall)?;
+
+        for (index, direntry) in out.iter_mut().enumerate() {
+            *direntry = self.read_dir_entry(index)?;
+        }
+
+        Ok(out)
+    }
+
+    // Indices of all files, in directory order
+    pub fn file_indices(&self) -> impl Iterator<Item = FileIndex> {
+        (0..self.num_files).map(FileIndex)
+    }
+
+    // Index of the file whose stored data contains storage offset off, or None if the
+    // offset is in the header, directory or padding.
+    pub fn file_at_storage_offset(&self, off: usize) -> Result<Option<usize>, Error<S::Error>> {
+        for index in 0..self.num_files as usize {
+            if self
+                .file_region(&self.read_dir_entry(index)?)?
+                .contains(&off)
+            {
+                return Ok(Some(index));
+            }
+        }
+
+        Ok(None)
+    }
+
+    // Open files from the last index down to 0
+    pub fn files_rev(
+        &self,
+    ) -> impl ExactSizeIterator<Item = Result<File<S>, Error<S::Error>>> + '_ {
+        (0..self.num_files as usize)
+            .rev()
+            .map(|index| self.open(index))
+    }
+
+    // Raw directory entry, for tools that need file layout. Not validated, use open()
+    // to access file data.
+    pub fn read_dir_entry(&self, index: usize) -> Result<DirEntry, Error<S::Error>> {
+        if index >= self.num_files as usize {
+            return Err(Error::InvalidFileIndex);
+        }
+
+        // Only read the leading part of the entry that we know how to parse
+        let mut buf = [0; size_of::<DirEntry>()];
+        let buf = &mut buf[..self.dir_entry_size.min(size_of::<DirEntry>())];
+        self.storage
+            .read(self.dir_offset + index * self.dir_entry_size, buf)?;
+
+        DirEntry::from_bytes(&mut &buf[..]).ok_or(Error::CorruptedFileSystem)
+    }
+
+    // Read the directory into scratch, sorted by file offset. Among entries at the same
+    // offset, shorter files go first.
+    fn entries_by_offset<'b>(
+        &self,
+        scratch: &'b mut [DirEntry],
+    ) -> Result<&'b [DirEntry], Error<S::Error>> {
+        let num_files = self.read_directory(scratch)?.len();
+        let entries = &mut scratch[..num_files];
+        entries.sort_unstable_by_key(|direntry| (direntry.offset, direntry.length));
+        Ok(entries)
+    }
+
+    // Storage range occupied by the file data
+    fn file_region(&self, direntry: &DirEntry) -> Result<Range<usize>, Error<S::Error>> {
+        entry_region(direntry, self.storage.capacity()).ok_or(Error::CorruptedFileSystem)
+    }
+}
+
+// Range occupied by the file data, None if it extends past limit or the entry is
+// inconsistent. Shared by open() and validate_prefix, so that both accept the same
+// entries.
+fn entry_region(direntry: &DirEntry, limit: usize) -> Option<Range<usize>> {
+    if direntry.logical_size() < direntry.length {
+        return None;
+    }
+
+    let start = direntry.offset as usize;
+    let end = start.checked_add(direntry.length as usize)?;
+    let alignment = direntry.alignment()?;
+    if end > limit || !start.is_multiple_of(alignment) {
+        return None;
+    }
+
+    Some(start..end)
+}
+
+// Result of FileSystem::fragmentation
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FragInfo {
+    // Number of unused ranges between file data regions
+    pub gaps: usize,
+    // Total size of these ranges
+    pub gap_bytes: usize,
+}
+
+// Result of FileSystem::validate_prefix
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrefixInfo {
+    // At least this many bytes are needed to validate the header and directory
+    Incomplete { needed: usize },
+    // Header and directory are valid, the complete image is image_size bytes
+    Complete { num_files: u16, image_size: usize },
+}
+
+impl<'a> FileSystem<&'a [u8]> {
+    // Validate the header and directory of an image being received, given its first
+    // bytes. File data is not needed, so a downloader can reject a bad image early.
+    pub fn validate_prefix(bytes: &'a [u8]) -> Result<PrefixInfo, Error<OutOfBounds>> {
+        if bytes.len() < size_of::<FilesystemHeader>() {
+            return Ok(PrefixInfo::Incomplete {
+                needed: size_of::<FilesystemHeader>(),
+            });
+        }
+
+        let header = read_header(&bytes, 0)?;
+        let image_size = header.image_size as usize;
+        let data_start = header.dir_end().ok_or(Error::CorruptedFileSystem)?;
+        if image_size < data_start {
+            return Err(Error::CorruptedFileSystem);
+        }
+        if bytes.len() < data_start {
+            return Ok(PrefixInfo::Incomplete { needed: data_start });
+        }
+        check_directory(&bytes, &header, header.dir_start())?;
+
+        let entry_size = header.dir_entry_size as usize;
+        for entry in bytes[header.dir_start()..data_start].chunks(entry_size) {
+            let direntry =
+                DirEntry::from_bytes(&mut &entry[..entry_size.min(size_of::<DirEntry>())])
+                    .ok_or(Error::CorruptedFileSystem)?;
+            let region = entry_region(&direntry, image_size).ok_or(Error::CorruptedFileSystem)?;
+            if region.start < data_start {
+                return Err(Error::CorruptedFileSystem);
+            }
+        }
+
+        Ok(PrefixInfo::Complete {
+            num_files: header.num_files,
+            image_size,
+        })
+    }
+}
+
+#[cfg(feature = "std")]
+impl FileSystem<BytesStorage> {
+    // File contents as a slice of the image, without copying the data.
+    // Sparse files are copied, to append their zero-filled tail.
+    pub fn file_bytes(&self, index: usize) -> Result<bytes::Bytes, Error<OutOfBounds>> {
+        let file = self.open(index)?;
+        let stored = self
+            .storage
+            .bytes
+            .slice(file.file_offset..file.file_offset + file.stored_size);
+        if file.stored_size == file.file_size {
+            return Ok(stored);
+        }
+
+        let mut contents = stored.to_vec();
+        contents.resize(file.file_size, 0);
+        Ok(contents.into())
+    }
+
+    // File data exactly as stored in the image, as a slice of the image. Unlike
+    // file_bytes(), the zero-filled tail of sparse files is not included.
+    pub fn file_region_bytes(&self, index: usize) -> Result<bytes::Bytes, Error<OutOfBounds>> {
+        let region = self.file_region(&self.read_dir_entry(index)?)?;
+        Ok(self.storage.bytes.slice(region))
+    }
+}
+
+#[derive(Debug)]
+pub struct File<'a, S> {
+    storage: &'a S,
+    file_offset: usize,
+    file_size: usize,
+    // Bytes present in storage, the rest of the file reads as zeros
+    stored_size: usize,
+    read_position: usize,
+    // Start of data already returned by read_chunks_rev()
+    rev_position: usize,
+    // Bytes that may still be read, see with_read_limit()
+    read_budget: usize,
+    content_type: [u8; DirEntry::CONTENT_TYPE_SIZE],
+    flags: u32,
+}
+
+// Clone gets an independent read position over the same file.
+// Not derived, because that would require S: Clone.
+impl<S> Clone for File<'_, S> {
+    fn clone(&self) -> Self {
+        Self {
+            storage: self.storage,
+            file_offset: self.file_offset,
+            file_size: self.file_size,
+            stored_size: self.stored_size,
+            read_position: self.read_position,
+            rev_position: self.rev_position,
+            read_budget: self.read_budget,
+            content_type: self.content_type,
+            flags: self.flags,
+        }
+    }
+}
+
+// Storage read size for File::read_with_interrupt
+pub const INTERRUPT_CHUNK_SIZE: usize = 512;
+
+impl<'a, S: Storage> File<'a, S> {
+    fn new(storage: &'a S, direntry: &DirEntry) -> Self {
+        Self {
+            storage,
+            file_offset: direntry.offset as usize,
+            file_size: direntry.logical_size() as usize,
+            stored_size: direntry.length as usize,
+            read_position: 0,
+            rev_position: direntry.logical_size() as usize,
+            read_budget: usize::MAX,
+            content_type: direntry.content_type,
+            flags: direntry.flags,
+        }
+    }
+
+    // Construct a file over an arbitrary storage region, e.g. for a custom container
+    // format. Fails with CorruptedFileSystem if the region does not fit in storage.
+    pub fn from_parts(storage: &'a S, offset: usize, size: usize) -> Result<Self, Error<S::Error>> {
+        let end = offset.checked_add(size).ok_or(Error::CorruptedFileSystem)?;
+        if end > storage.capacity() {
+            return Err(Error::CorruptedFileSystem);
+        }
+
+        Ok(Self {
+            storage,
+            file_offset: offset,
+            file_size: size,
+            stored_size: size,
+            read_position: 0,
+            rev_position: size,
+            read_budget: usize::MAX,
+            content_type: [0; DirEntry::CONTENT_TYPE_SIZE],
+            flags: 0,
+        })
+    }
+
+    // Cap the total number of bytes that can be read from this file, in any direction.
+    // Once the limit is reached, reads return Ok(0) even if the file has more data.
+    // A clone gets its own copy of the remaining limit.
+    pub fn with_read_limit(mut self, limit: usize) -> Self {
+        self.read_budget = self.read_budget.min(limit);
+        self
+    }
+
+    pub fn total_size(&self) -> usize {
+        self.file_size
+    }
+
+    // MIME type of the file, if set by the builder.
+    pub fn content_type(&self) -> Option<&str> {
+        content_type_str(&self.content_type)
+    }
+
+    // Per-file flags, as set by the builder
+    pub fn flags(&self) -> u32 {
+        self.flags
+    }
+
+    // Storage offset of the file data. Files opened from a directory entry are aligned
+    // as required by the entry.
+    pub fn storage_offset(&self) -> usize {
+        self.file_offset
+    }
+
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error<S::Error>> {
+        let bytes_to_read = buf.len().min(self.forward_remaining());
+
+        if bytes_to_read > 0 {
+            self.read_at(self.read_position, &mut buf[..bytes_to_read])?;
+            self.advance(bytes_to_read);
+        }
+
+        Ok(bytes_to_read)
+    }
+
+    // Fill first, then second, from consecutive file bytes, e.g. for DMA double
+    // buffering. Takes one storage read per buffer. Returns the total bytes read,
+    // second is only used once first is full.
+    pub fn read_split(
+        &mut self,
+        first: &mut [u8],
+        second: &mut [u8],
+    ) -> Result<usize, Error<S::Error>> {
+        let first_len = self.read(first)?;
+        if first_len < first.len() {
+            return Ok(first_len);
+        }
+
+        Ok(first_len + self.read(second)?)
+    }
 
-// Backend storage API. Originally from littlefs2 crate.
-pub trait Storage {
-    // Error type
-    type Error;
+    // Read into the part of buf starting at the first address aligned to align bytes,
+    // for DMA targets with alignment requirements. Returns the range of buf that was
+    // filled, empty if buf has no aligned part.
+    pub fn read_aligned(
+        &mut self,
+        buf: &mut [u8],
+        align: usize,
+    ) -> Result<Range<usize>, Error<S::Error>> {
+        let align = align.max(1);
+        let address = buf.as_ptr() as usize;
+        let start = ((align - address % align) % align).min(buf.len());
 
-    // Total storage size in bytes.
-    fn capacity(&self) -> usize;
+        let bytes_read = self.read(&mut buf[start..])?;
+        Ok(start..start + bytes_read)
+    }
 
-    // Read data from the storage device.
-    // Guaranteed not to be called with off > capacity() or bufs of length > capacity() - off.
-    fn read(&self, off: usize, buf: &mut [u8]) -> Result<(), Self::Error>;
-}
+    // Same as read(), but XORs the data with key repeated from the start of the file,
+    // to undo simple obfuscation. Masking depends only on the file position, so data can
+    // be read in chunks of any size. An empty key leaves the data as is.
+    pub fn read_xored(&mut self, buf: &mut [u8], key: &[u8]) -> Result<usize, Error<S::Error>> {
+        let position = self.read_position;
+        let bytes_read = self.read(buf)?;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-pub enum Error<E> {
-    InvalidSignature,
-    CorruptedFileSystem,
-    InvalidFileIndex,
-    Storage(E),
-}
+        if !key.is_empty() {
+            for (offset, byte) in buf[..bytes_read].iter_mut().enumerate() {
+                *byte ^= key[(position + offset) % key.len()];
+            }
+        }
 
-impl<E> From<E> for Error<E> {
-    fn from(error: E) -> Self {
-        Error::Storage(error)
+        Ok(bytes_read)
     }
-}
 
-pub struct FileSystem<S> {
-    storage: S,
-    num_files: u16,
-}
+    // Compare the rest of the file, from the current read position, with expected.
+    // Stops reading at the first mismatch.
+    pub fn equals(&mut self, expected: &[u8]) -> Result<bool, Error<S::Error>> {
+        if self.forward_remaining() != expected.len() {
+            return Ok(false);
+        }
 
-impl<S: Storage> FileSystem<S> {
-    pub fn mount(storage: S) -> Result<Self, Error<S::Error>> {
-        if storage.capacity() < size_of::<FilesystemHeader>() {
-            return Err(Error::CorruptedFileSystem);
+        let mut buf = [0; 64];
+        for expected_chunk in expected.chunks(buf.len()) {
+            let chunk = &mut buf[..expected_chunk.len()];
+            self.read(chunk)?;
+            if chunk != expected_chunk {
+                return Ok(false);
+            }
         }
 
-        let mut buf = [0; size_of::<FilesystemHeader>()];
-        storage.read(0, &mut buf)?;
-        let header =
-            FilesystemHeader::from_bytes(&mut buf.as_slice()).ok_or(Error::CorruptedFileSystem)?;
+        Ok(true)
+    }
 
-        if header.signature != SIGNATURE {
-            return Err(Error::InvalidSignature);
+    // Read the rest of the file into scratch, one chunk at a time, and pass each chunk
+    // to f.
+    pub fn for_each_chunk<F: FnMut(&[u8])>(
+        &mut self,
+        scratch: &mut [u8],
+        mut f: F,
+    ) -> Result<(), Error<S::Error>> {
+        if scratch.is_empty() && self.forward_remaining() > 0 {
+            return Err(Error::BufferTooSmall);
         }
 
-        if storage.capacity()
-            < size_of::<FilesystemHeader>() + header.num_files as usize * size_of::<DirEntry>()
-        {
-            return Err(Error::CorruptedFileSystem);
+        loop {
+            let len = self.read(scratch)?;
+            if len == 0 {
+                return Ok(());
+            }
+            f(&scratch[..len]);
         }
+    }
 
-        Ok(FileSystem {
-            storage,
-            num_files: header.num_files,
-        })
+    // Read the rest of the file into a bounded vector. Fails with BufferTooSmall if more
+    // than N bytes remain.
+    #[cfg(feature = "heapless")]
+    pub fn read_to_arrayvec<const N: usize>(
+        &mut self,
+    ) -> Result<heapless::Vec<u8, N>, Error<S::Error>> {
+        let mut contents = heapless::Vec::new();
+        contents
+            .resize(self.forward_remaining(), 0)
+            .map_err(|_| Error::BufferTooSmall)?;
+        self.read(&mut contents)?;
+
+        Ok(contents)
     }
 
-    pub fn get_num_files(&self) -> u16 {
-        self.num_files
+    // Read up to len bytes into buf and return the part of buf that was filled.
+    pub fn read_view<'b>(
+        &mut self,
+        buf: &'b mut [u8],
+        len: usize,
+    ) -> Result<&'b [u8], Error<S::Error>> {
+        let buf_len = buf.len();
+        let bytes_read = self.read(&mut buf[..len.min(buf_len)])?;
+        Ok(&buf[..bytes_read])
     }
 
-    pub fn open(&self, index: usize) -> Result<File<S>, Error<S::Error>> {
-        if index >= self.num_files as usize {
-            return Err(Error::InvalidFileIndex);
-        }
+    // Read a NUL-terminated UTF-8 string into buf and advance past the NUL. Fails with
+    // BufferTooSmall if the string doesn't fit in buf, or UnexpectedEof if the file ends
+    // before the NUL. On error the read position is left unchanged.
+    pub fn read_cstr<'b>(&mut self, buf: &'b mut [u8]) -> Result<&'b str, Error<S::Error>> {
+        let bytes_read = buf.len().min(self.forward_remaining());
+        self.read_at(self.read_position, &mut buf[..bytes_read])?;
 
-        let mut buf = [0; size_of::<DirEntry>()];
-        self.storage.read(
-            size_of::<FilesystemHeader>() + index * size_of::<DirEntry>(),
-            &mut buf,
-        )?;
+        // Only the string and its NUL are consumed, the rest of buf is read ahead
+        let Some(len) = buf[..bytes_read].iter().position(|&c| c == 0) else {
+            return Err(if bytes_read == buf.len() {
+                Error::BufferTooSmall
+            } else {
+                Error::UnexpectedEof
+            });
+        };
 
-        let direntry =
-            DirEntry::from_bytes(&mut buf.as_slice()).ok_or(Error::CorruptedFileSystem)?;
-        if direntry.offset as usize + direntry.length as usize > self.storage.capacity() {
-            return Err(Error::CorruptedFileSystem);
+        match core::str::from_utf8(&buf[..len]) {
+            Ok(string) => {
+                self.advance(len + 1);
+                Ok(string)
+            }
+            Err(_) => Err(Error::InvalidUtf8),
         }
+    }
 
-        return Ok(File::new(&self.storage, &direntry));
+    // Absolute storage range that read() with a buffer of len bytes would access,
+    // empty at the end of the file or in the zero-filled tail of a sparse file.
+    pub fn next_read_range(&self, len: usize) -> Range<usize> {
+        let end = self.read_position + len.min(self.forward_remaining());
+        let start = self.read_position.min(self.stored_size);
+        self.file_offset + start..self.file_offset + end.min(self.stored_size).max(start)
     }
-}
 
-#[derive(Debug)]
-pub struct File<'a, S> {
-    storage: &'a S,
-    file_offset: usize,
-    file_size: usize,
-    read_position: usize,
-}
+    // Same as read(), but reads from storage in chunks of INTERRUPT_CHUNK_SIZE and calls
+    // should_cancel between them. If it returns true, the read stops with Interrupted.
+    // Data read so far is in buf and the read position is advanced past it.
+    pub fn read_with_interrupt<F: FnMut() -> bool>(
+        &mut self,
+        buf: &mut [u8],
+        mut should_cancel: F,
+    ) -> Result<usize, Error<S::Error>> {
+        let bytes_to_read = buf.len().min(self.forward_remaining());
 
-impl<'a, S: Storage> File<'a, S> {
-    fn new(storage: &'a S, direntry: &DirEntry) -> Self {
-        Self {
-            storage,
-            file_offset: direntry.offset as usize,
-            file_size: direntry.length as usize,
-            read_position: 0,
+        let mut bytes_read = 0;
+        for chunk in buf[..bytes_to_read].chunks_mut(INTERRUPT_CHUNK_SIZE) {
+            if bytes_read > 0 && should_cancel() {
+                return Err(Error::Interrupted { bytes_read });
+            }
+
+            self.read_at(self.read_position, chunk)?;
+            self.advance(chunk.len());
+            bytes_read += chunk.len();
         }
+
+        Ok(bytes_read)
     }
 
-    pub fn total_size(&self) -> usize {
-        self.file_size
+    // Same as read(), but never reads more than max_chunk bytes from storage at once,
+    // for devices with a limited transfer size.
+    pub fn read_adaptive(
+        &mut self,
+        buf: &mut [u8],
+        max_chunk: usize,
+    ) -> Result<usize, Error<S::Error>> {
+        let bytes_to_read = buf.len().min(self.forward_remaining());
+
+        for chunk in buf[..bytes_to_read].chunks_mut(max_chunk.max(1)) {
+            self.read_at(self.read_position, chunk)?;
+            self.advance(chunk.len());
+        }
+
+        Ok(bytes_to_read)
     }
 
-    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error<S::Error>> {
-        let max_read = self.file_size - self.read_position;
-        let bytes_to_read = buf.len().min(max_read);
+    // Read up to len bytes into a circular buffer, starting at *head and wrapping around
+    // the end of ring. Advances *head past the data read. At most ring.len() bytes are
+    // read per call.
+    pub fn read_into_ring(
+        &mut self,
+        ring: &mut [u8],
+        head: &mut usize,
+        len: usize,
+    ) -> Result<usize, Error<S::Error>> {
+        if ring.is_empty() {
+            return Err(Error::BufferTooSmall);
+        }
 
-        if bytes_to_read > 0 {
-            self.storage.read(
-                self.file_offset + self.read_position,
-                &mut buf[..bytes_to_read],
+        let bytes_to_read = len.min(ring.len()).min(self.forward_remaining());
+        let start = *head % ring.len();
+
+        let first_len = bytes_to_read.min(ring.len() - start);
+        let (wrapped, tail) = ring.split_at_mut(start);
+        if first_len > 0 {
+            self.read_at(self.read_position, &mut tail[..first_len])?;
+        }
+        if bytes_to_read > first_len {
+            self.read_at(
+                self.read_position + first_len,
+                &mut wrapped[..bytes_to_read - first_len],
             )?;
+        }
+
+        self.advance(bytes_to_read);
+        *head = (start + bytes_to_read) % ring.len();
+
+        Ok(bytes_to_read)
+    }
+
+    // Read the first N bytes of the file, e.g. to check the magic number of its format,
+    // and leave the read position right after them. Fails with UnexpectedEof if the file
+    // is shorter than N bytes.
+    pub fn read_header<const N: usize>(&mut self) -> Result<[u8; N], Error<S::Error>> {
+        if self.file_size < N || self.read_budget < N {
+            return Err(Error::UnexpectedEof);
+        }
+
+        let mut header = [0; N];
+        self.read_at(0, &mut header)?;
+        self.read_position = N;
+        self.read_budget -= N;
+
+        Ok(header)
+    }
+
+    // Read the next N bytes as a fixed-size record. Returns None at the end of the file
+    // and UnexpectedEof if fewer than N bytes remain, leaving the read position as is.
+    pub fn read_record<const N: usize>(&mut self) -> Result<Option<[u8; N]>, Error<S::Error>> {
+        let remaining = self.forward_remaining();
+        if remaining == 0 {
+            return Ok(None);
+        }
+        if remaining < N {
+            return Err(Error::UnexpectedEof);
+        }
+
+        let mut record = [0; N];
+        self.read_at(self.read_position, &mut record)?;
+        self.advance(N);
+
+        Ok(Some(record))
+    }
+
+    // Read the next N bytes at the read position, for parsing fixed binary structs.
+    // Convert the result with e.g. u64::from_le_bytes, or split it into fields first.
+    // Fails with UnexpectedEof if fewer than N bytes remain, leaving the read position
+    // as is.
+    pub fn read_pod<const N: usize>(&mut self) -> Result<[u8; N], Error<S::Error>> {
+        if self.forward_remaining() < N {
+            return Err(Error::UnexpectedEof);
+        }
+
+        let mut value = [0; N];
+        self.read_at(self.read_position, &mut value)?;
+        self.advance(N);
 
-            self.read_position += bytes_to_read;
+        Ok(value)
+    }
+
+    // Read the file backwards, up to chunk bytes at a time. Each call returns the chunk
+    // preceding the one returned by the previous call, starting from the end of the file.
+    // Uses its own cursor, independent of read().
+    pub fn read_chunks_rev(
+        &mut self,
+        chunk: usize,
+        buf: &mut [u8],
+    ) -> Result<usize, Error<S::Error>> {
+        let bytes_to_read = chunk
+            .min(buf.len())
+            .min(self.rev_position)
+            .min(self.read_budget);
+
+        if bytes_to_read > 0 {
+            self.read_at(self.rev_position - bytes_to_read, &mut buf[..bytes_to_read])?;
+            self.rev_position -= bytes_to_read;
+            self.read_budget -= bytes_to_read;
         }
 
         Ok(bytes_to_read)
     }
+
+    // Bytes available to forward reads
+    fn forward_remaining(&self) -> usize {
+        (self.file_size - self.read_position).min(self.read_budget)
+    }
+
+    // Move the read position forward past len bytes consumed by the caller, and charge
+    // them to the read limit.
+    fn advance(&mut self, len: usize) {
+        self.read_position += len;
+        self.read_budget = self.read_budget.saturating_sub(len);
+    }
+
+    // Read file data at position, which must be within the file. Doesn't move the read
+    // position or charge the read limit, callers do that for the bytes they consume.
+    // The part past stored_size is zero-filled without accessing storage.
+    fn read_at(&self, position: usize, buf: &mut [u8]) -> Result<(), Error<S::Error>> {
+        let stored_len = self.stored_size.saturating_sub(position).min(buf.len());
+        let (stored, zeros) = buf.split_at_mut(stored_len);
+        if !stored.is_empty() {
+            self.storage.read(self.file_offset + position, stored)?;
+        }
+        zeros.fill(0);
+        Ok(())
+    }
 }
 
 // Filesystem header, expected at storage offset 0
 #[repr(packed(1))]
 pub struct FilesystemHeader {
     pub signature: u64, // "SimpleFS"
+    pub version: u16,   // FORMAT_VERSION
     pub num_files: u16,
+    pub dir_entry_size: u16,
+    pub image_crc: u32, // CRC32 of the whole image, excluding this field
+    pub build_time: u64,
+    pub header_crc: u32, // CRC32 of the header, excluding image_crc and this field
+    pub flags: u32,      // Raw HeaderFlags bits
+    pub dir_offset: u32, // Start of the directory, 0 if it directly follows the header
+    pub dir_crc: u32,    // CRC32 of the directory
+    // Largest logical file size, for sizing read buffers
+    pub largest_file: u32,
+    // Total image size, including padding and the backup copy
+    pub image_size: u32,
 }
 
 impl FilesystemHeader {
+    // Location of CRC fields within the header
+    pub const IMAGE_CRC_OFFSET: usize = 14;
+    pub const HEADER_CRC_OFFSET: usize = 26;
+
     pub fn from_bytes(reader: &mut impl Buf) -> Option<Self> {
         if reader.remaining() < size_of::<FilesystemHeader>() {
             return None;
         }
 
         let signature = reader.get_u64();
+        let version = reader.get_u16();
         let num_files = reader.get_u16();
+        let dir_entry_size = reader.get_u16();
+        let image_crc = reader.get_u32();
+        let build_time = reader.get_u64();
+        let header_crc = reader.get_u32();
+        let flags = reader.get_u32();
+        let dir_offset = reader.get_u32();
+        let dir_crc = reader.get_u32();
+        let largest_file = reader.get_u32();
+        let image_size = reader.get_u32();
 
         Some(FilesystemHeader {
             signature,
+            version,
             num_files,
+            dir_entry_size,
+            image_crc,
+            build_time,
+            header_crc,
+            flags,
+            dir_offset,
+            dir_crc,
+            largest_file,
+            image_size,
         })
     }
 
     pub fn to_bytes(&self, writer: &mut impl BufMut) {
         writer.put_u64(self.signature);
+        writer.put_u16(self.version);
         writer.put_u16(self.num_files);
+        writer.put_u16(self.dir_entry_size);
+        writer.put_u32(self.image_crc);
+        writer.put_u64(self.build_time);
+        writer.put_u32(self.header_crc);
+        writer.put_u32(self.flags);
+        writer.put_u32(self.dir_offset);
+        writer.put_u32(self.dir_crc);
+        writer.put_u32(self.largest_file);
+        writer.put_u32(self.image_size);
+    }
+
+    // Compute CRC to be stored in header_crc. image_crc is excluded, because the
+    // image CRC covers the header itself.
+    pub fn crc(&self) -> u32 {
+        let mut buf = [0; size_of::<FilesystemHeader>()];
+        self.to_bytes(&mut &mut buf[..]);
+
+        let mut crc = Crc32::new();
+        crc.update(&buf[..Self::IMAGE_CRC_OFFSET]);
+        crc.update(&buf[Self::IMAGE_CRC_OFFSET + size_of::<u32>()..Self::HEADER_CRC_OFFSET]);
+        crc.update(&buf[Self::HEADER_CRC_OFFSET + size_of::<u32>()..]);
+        crc.finalize()
+    }
+
+    // Same as from_bytes, but usable in const context to check embedded images at compile time.
+    pub const fn from_slice(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < size_of::<FilesystemHeader>() {
+            return None;
+        }
+
+        Some(FilesystemHeader {
+            signature: const_get_u64(bytes, 0),
+            version: const_get_u16(bytes, 8),
+            num_files: const_get_u16(bytes, 10),
+            dir_entry_size: const_get_u16(bytes, 12),
+            image_crc: const_get_u32(bytes, 14),
+            build_time: const_get_u64(bytes, 18),
+            header_crc: const_get_u32(bytes, 26),
+            flags: const_get_u32(bytes, 30),
+            dir_offset: const_get_u32(bytes, 34),
+            dir_crc: const_get_u32(bytes, 38),
+            largest_file: const_get_u32(bytes, 42),
+            image_size: const_get_u32(bytes, 46),
+        })
+    }
+
+    // Directory size in bytes, None if it doesn't fit in usize on 16-bit targets.
+    pub fn dir_size(&self) -> Option<usize> {
+        (self.num_files as usize).checked_mul(self.dir_entry_size as usize)
+    }
+
+    // Image offset of the directory. May be past the header if the builder aligned it.
+    pub fn dir_start(&self) -> usize {
+        if self.dir_offset == 0 {
+            size_of::<FilesystemHeader>()
+        } else {
+            self.dir_offset as usize
+        }
+    }
+
+    // End of the directory, where file data starts
+    pub fn dir_end(&self) -> Option<usize> {
+        self.dir_size()?.checked_add(self.dir_start())
+    }
+
+    pub const fn has_valid_signature(&self) -> bool {
+        self.signature == SIGNATURE
     }
 }
 
+// Image-wide options, stored in FilesystemHeader::flags
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct HeaderFlags(u32);
+
+impl HeaderFlags {
+    // Reserved for compressed file data, images with it set can't be mounted
+    pub const COMPRESSED: u32 = 1 << 0;
+    // Reserved for encrypted file data, images with it set can't be mounted
+    pub const ENCRYPTED: u32 = 1 << 1;
+    // Image ends with a copy of the directory and header
+    pub const BACKUP_HEADER: u32 = 1 << 2;
+
+    pub const fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    pub const fn bits(&self) -> u32 {
+        self.0
+    }
+
+    pub const fn with(self, flag: u32) -> Self {
+        Self(self.0 | flag)
+    }
+
+    pub const fn is_compressed(&self) -> bool {
+        self.0 & Self::COMPRESSED != 0
+    }
+
+    pub const fn is_encrypted(&self) -> bool {
+        self.0 & Self::ENCRYPTED != 0
+    }
+
+    pub const fn has_backup_header(&self) -> bool {
+        self.0 & Self::BACKUP_HEADER != 0
+    }
+}
+
+// Big-endian integer readers for const fns, where Buf can't be used.
+// Caller guarantees that the slice is long enough.
+const fn const_get_u16(bytes: &[u8], off: usize) -> u16 {
+    (bytes[off] as u16) << 8 | bytes[off + 1] as u16
+}
+
+const fn const_get_u32(bytes: &[u8], off: usize) -> u32 {
+    (const_get_u16(bytes, off) as u32) << 16 | const_get_u16(bytes, off + 2) as u32
+}
+
+const fn const_get_u64(bytes: &[u8], off: usize) -> u64 {
+    let mut value = 0;
+    let mut i = 0;
+    while i < 8 {
+        value = value << 8 | bytes[off + i] as u64;
+        i += 1;
+    }
+    value
+}
+
 // "SimpleFS"
 pub const SIGNATURE: u64 = 0x53696d706c654653;
 
+// Layout of the header and directory, bumped on changes that readers of older images
+// can't handle. Images with a different version fail to mount with UnsupportedVersion.
+pub const FORMAT_VERSION: u16 = 1;
+
 // Directory entry, 0 or more follow filesystem header.
+// Stored entries are dir_entry_size bytes long, fields not listed here are ignored.
+// Entries written by older builders may lack fields after length, these are zeroed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[repr(packed(1))]
 pub struct DirEntry {
     pub offset: u32,
     pub length: u32,
+    pub content_type: [u8; DirEntry::CONTENT_TYPE_SIZE], // MIME type, NUL padded
+    // File size including the zero-filled tail not stored in the image, see logical_size()
+    pub logical_length: u32,
+    // Application-defined, stored verbatim
+    pub flags: u32,
+    // Required alignment of offset, as log2 of the alignment in bytes
+    pub align_log2: u8,
 }
 
 impl DirEntry {
+    // Size of offset and length, present in every entry
+    pub const MIN_SIZE: usize = 8;
+    pub const CONTENT_TYPE_SIZE: usize = 32;
+
     pub fn from_bytes(reader: &mut impl Buf) -> Option<Self> {
-        if reader.remaining() < size_of::<DirEntry>() {
+        if reader.remaining() < DirEntry::MIN_SIZE {
             return None;
         }
 
-        let offset = reader.get_u32();
-        let length = reader.get_u32();
+        let mut direntry = DirEntry {
+            offset: reader.get_u32(),
+            length: reader.get_u32(),
+            ..DirEntry::default()
+        };
+
+        // Entries end after the last field their builder knew about. If a field doesn't
+        // fit, neither do the ones after it, so they are all left zeroed.
+        if reader.remaining() < DirEntry::CONTENT_TYPE_SIZE {
+            return Some(direntry);
+        }
+        reader.copy_to_slice(&mut direntry.content_type);
+
+        if reader.remaining() < size_of::<u32>() {
+            return Some(direntry);
+        }
+        direntry.logical_length = reader.get_u32();
+
+        if reader.remaining() < size_of::<u32>() {
+            return Some(direntry);
+        }
+        direntry.flags = reader.get_u32();
+
+        if reader.remaining() >= size_of::<u8>() {
+            direntry.align_log2 = reader.get_u8();
+        }
 
-        Some(DirEntry { offset, length })
+        Some(direntry)
     }
 
     pub fn to_bytes(&self, writer: &mut impl BufMut) {
         writer.put_u32(self.offset);
         writer.put_u32(self.length);
+        writer.put_slice(&self.content_type);
+        writer.put_u32(self.logical_length);
+        writer.put_u32(self.flags);
+        writer.put_u8(self.align_log2);
+    }
+
+    // Size of the file as seen by readers. Sparse files have logical_length larger than
+    // length, the rest is read as zeros. Zero logical_length means the file isn't sparse.
+    pub fn logical_size(&self) -> u32 {
+        if self.logical_length == 0 {
+            self.length
+        } else {
+            self.logical_length
+        }
+    }
+
+    pub fn content_type(&self) -> Option<&str> {
+        content_type_str(&self.content_type)
+    }
+
+    // Required alignment of offset in bytes, None if too large to represent
+    pub fn alignment(&self) -> Option<usize> {
+        1usize.checked_shl(self.align_log2 as u32)
+    }
+}
+
+fn content_type_str(bytes: &[u8]) -> Option<&str> {
+    let len = bytes.iter().position(|&c| c == 0).unwrap_or(bytes.len());
+    match core::str::from_utf8(&bytes[..len]) {
+        Ok(content_type) if !content_type.is_empty() => Some(content_type),
+        _ => None,
+    }
+}
+
+// CRC-32 (IEEE 802.3), same as used by zlib and gzip.
+pub struct Crc32 {
+    value: u32,
+}
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Self { value: !0 }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.value =
+                CRC32_TABLE[((self.value ^ byte as u32) & 0xff) as usize] ^ (self.value >> 8);
+        }
+    }
+
+    pub fn finalize(&self) -> u32 {
+        !self.value
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut value = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            value = if value & 1 != 0 {
+                0xedb88320 ^ (value >> 1)
+            } else {
+                value >> 1
+            };
+            bit += 1;
+        }
+        table[i] = value;
+        i += 1;
+    }
+    table
+}
+
+// SHA-256 (FIPS 180-4), for verifying images against a trusted digest.
+#[cfg(feature = "sha256")]
+pub struct Sha256 {
+    state: [u32; 8],
+    block: [u8; 64],
+    block_len: usize,
+    total_len: u64,
+}
+
+#[cfg(feature = "sha256")]
+impl Sha256 {
+    pub const DIGEST_SIZE: usize = 32;
+
+    pub fn new() -> Self {
+        Self {
+            state: [
+                0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+                0x5be0cd19,
+            ],
+            block: [0; 64],
+            block_len: 0,
+            total_len: 0,
+        }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.total_len += data.len() as u64;
+        while !data.is_empty() {
+            let len = (64 - self.block_len).min(data.len());
+            self.block[self.block_len..][..len].copy_from_slice(&data[..len]);
+            self.block_len += len;
+            data = &data[len..];
+
+            if self.block_len == 64 {
+                self.compress();
+                self.block_len = 0;
+            }
+        }
+    }
+
+    pub fn finalize(mut self) -> [u8; Self::DIGEST_SIZE] {
+        let bit_len = self.total_len * 8;
+        self.update(&[0x80]);
+        while self.block_len != 56 {
+            self.update(&[0]);
+        }
+        self.update(&bit_len.to_be_bytes());
+
+        let mut digest = [0; Self::DIGEST_SIZE];
+        for (chunk, word) in digest.chunks_exact_mut(4).zip(self.state) {
+            chunk.copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+
+    fn compress(&mut self) {
+        let mut w = [0u32; 64];
+        for (word, chunk) in w.iter_mut().zip(self.block.chunks_exact(4)) {
+            *word = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.state;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(SHA256_K[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+
+        for (word, value) in self.state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *word = word.wrapping_add(value);
+        }
+    }
+}
+
+#[cfg(feature = "sha256")]
+impl Default for Sha256 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "sha256")]
+const SHA256_K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+// CRC of a complete in-memory image, as stored in FilesystemHeader::image_crc.
+pub fn image_crc(image: &[u8]) -> u32 {
+    let crc_end = FilesystemHeader::IMAGE_CRC_OFFSET + size_of::<u32>();
+
+    let mut crc = Crc32::new();
+    crc.update(&image[..FilesystemHeader::IMAGE_CRC_OFFSET.min(image.len())]);
+    crc.update(&image[crc_end.min(image.len())..]);
+    crc.finalize()
+}
+
+// Image offset of the primary directory entry for index, for images with entries of
+// size_of::<DirEntry>() and the directory right after the header, as written by the
+// builders in this crate without directory alignment.
+pub const fn dir_entry_offset(index: usize) -> usize {
+    size_of::<FilesystemHeader>() + index * size_of::<DirEntry>()
+}
+
+// Image builder for no_std targets, writing directly into a caller-provided buffer.
+// The number of files is fixed upfront to reserve space for the directory; file data
+// is copied into the buffer as files are added.
+pub struct CoreBuilder<'a> {
+    buf: &'a mut [u8],
+    num_files: u16,
+    files_added: u16,
+    data_end: usize,
+    build_time: u64,
+    largest_file: u32,
+}
+
+impl<'a> CoreBuilder<'a> {
+    pub fn new(buf: &'a mut [u8], num_files: u16) -> Result<Self, Error<Infallible>> {
+        let data_end = size_of::<FilesystemHeader>() + num_files as usize * size_of::<DirEntry>();
+        if data_end > buf.len() {
+            return Err(Error::BufferTooSmall);
+        }
+
+        Ok(Self {
+            buf,
+            num_files,
+            files_added: 0,
+            data_end,
+            build_time: 0,
+            largest_file: 0,
+        })
+    }
+
+    pub fn set_build_time(&mut self, build_time: u64) {
+        self.build_time = build_time;
+    }
+
+    // Fails with InvalidFileIndex if all files reserved in new() were already added,
+    // or with FileTooLarge if the image would grow past the u32 offsets of the format.
+    pub fn add_file(&mut self, data: &[u8]) -> Result<(), Error<Infallible>> {
+        if self.files_added == self.num_files {
+            return Err(Error::InvalidFileIndex);
+        }
+
+        let data_end = self.data_end + data.len();
+        if data_end > u32::MAX as usize {
+            return Err(Error::FileTooLarge);
+        }
+
+        let direntry = DirEntry {
+            offset: self.data_end as u32,
+            length: data.len() as u32,
+            content_type: [0; DirEntry::CONTENT_TYPE_SIZE],
+            logical_length: 0,
+            flags: 0,
+            align_log2: 0,
+        };
+        self.buf
+            .get_mut(self.data_end..data_end)
+            .ok_or(Error::BufferTooSmall)?
+            .copy_from_slice(data);
+
+        let entry_position = dir_entry_offset(self.files_added as usize);
+        direntry.to_bytes(&mut &mut self.buf[entry_position..]);
+
+        self.files_added += 1;
+        self.data_end = data_end;
+        self.largest_file = self.largest_file.max(direntry.length);
+        Ok(())
+    }
+
+    // Write the header and return the image size. If fewer files were added than
+    // reserved, the unused directory space is left in the image.
+    pub fn finalize(self) -> usize {
+        let mut header = FilesystemHeader {
+            signature: SIGNATURE,
+            version: FORMAT_VERSION,
+            num_files: self.files_added,
+            dir_entry_size: size_of::<DirEntry>() as u16,
+            image_crc: 0,
+            build_time: self.build_time,
+            header_crc: 0,
+            flags: HeaderFlags::default().bits(),
+            dir_offset: size_of::<FilesystemHeader>() as u32,
+            dir_crc: 0,
+            largest_file: self.largest_file,
+            image_size: self.data_end as u32,
+        };
+        let dir_end = dir_entry_offset(self.files_added as usize);
+        let mut dir_crc = Crc32::new();
+        dir_crc.update(&self.buf[size_of::<FilesystemHeader>()..dir_end]);
+        header.dir_crc = dir_crc.finalize();
+        header.header_crc = header.crc();
+        header.to_bytes(&mut &mut self.buf[..]);
+
+        header.image_crc = image_crc(&self.buf[..self.data_end]);
+        header.to_bytes(&mut &mut self.buf[..]);
+
+        self.data_end
+    }
+}
+
+// Fuzzing entry point: mount arbitrary bytes and read every file, in both directions.
+// Errors are expected and ignored, but the parser must never panic.
+#[cfg(feature = "fuzzing")]
+pub fn mount_and_read_all(image: &[u8]) {
+    let mut entries = [DirEntry::default(); 64];
+    let Ok(fs) = FileSystem::mount_eager(image, &mut entries).or_else(|_| FileSystem::mount(image))
+    else {
+        return;
+    };
+
+    let mut buf = [0; 64];
+    for index in 0..fs.get_num_files() as usize {
+        let Ok(file) = fs.open(index) else {
+            continue;
+        };
+        // Sparse files can be much larger than the image, don't spend all the time on them
+        let mut file = file.with_read_limit(2 * image.len());
+
+        let _ = file.content_type();
+        while let Ok(len) = file.read(&mut buf) {
+            if len == 0 {
+                break;
+            }
+        }
+        while let Ok(len) = file.read_chunks_rev(buf.len(), &mut buf) {
+            if len == 0 {
+                break;
+            }
+        }
     }
 }
 
-const _HDR_SIZE_CHECK: [u8; 10] = [0; size_of::<FilesystemHeader>()];
-const _DIRENTRY_SIZE_CHECK: [u8; 8] = [0; size_of::<DirEntry>()];
+const _HDR_SIZE_CHECK: [u8; 50] = [0; size_of::<FilesystemHeader>()];
+const _DIRENTRY_SIZE_CHECK: [u8; 49] = [0; size_of::<DirEntry>()];